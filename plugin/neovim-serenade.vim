@@ -24,10 +24,32 @@ endif
 " RPC message constants
 let s:SerenadeStop = 'serenade_stop'
 let s:SerenadeStart = 'serenade_start'
+let s:SerenadeRevert = 'serenade_revert'
+let s:SerenadeSnapshots = 'serenade_snapshots'
+let s:SerenadeShutdown = 'serenade_shutdown'
+let s:SerenadeFocus = 'serenade_focus'
+let s:SerenadeBlur = 'serenade_blur'
+let s:SerenadeStatus = 'serenade_status'
+let s:SerenadeReconnect = 'serenade_reconnect'
+let s:SerenadeReload = 'serenade_reload'
 
 " Entry point
 function! s:init()
-  call s:connect()
+  if get(g:, 'serenade_daemon', 0)
+    call s:register()
+  else
+    call s:connect()
+  endif
+endfunction
+
+" Hand this instance to the shared daemon, starting it if needed. The daemon
+" defines the commands itself once it has attached.
+function! s:register()
+  let jobID = jobstart([s:bin, '--daemon', '--register', v:servername], { 'detach': v:true, 'on_stderr': function('s:OnStderr') })
+
+  if 0 >= jobID
+    echoerr "serenade: cannot start daemon"
+  endif
 endfunction
 
 " Get the Job ID and check for errors. If no errors, attach RPC handlers to
@@ -64,11 +86,28 @@ endfunction
 function! s:AttachRPCHandlers(jobID)
   command! -nargs=0 SerenadeStart :call s:rpc(s:SerenadeStart)
   command! -nargs=0 SerenadeStop :call s:rpc(s:SerenadeStop)
+  command! -nargs=? SerenadeRevert :call s:rpc(s:SerenadeRevert, <q-args>)
+  command! -nargs=0 SerenadeSnapshots :call s:rpc(s:SerenadeSnapshots)
+  command! -nargs=0 SerenadeStatus :echo s:request(s:SerenadeStatus)
+  command! -nargs=0 SerenadeReconnect :call s:rpc(s:SerenadeReconnect)
+  command! -nargs=0 SerenadeReload :call s:rpc(s:SerenadeReload)
+
+  augroup serenade
+    autocmd!
+    autocmd VimLeavePre * call s:rpc(s:SerenadeShutdown)
+    autocmd FocusGained * call s:rpc(s:SerenadeFocus)
+    autocmd FocusLost * call s:rpc(s:SerenadeBlur)
+  augroup END
 endfunction
 
 " Send an RPC message to the remote process.
-function! s:rpc(rpcMessage)
-	call rpcnotify(s:serenadejobid, a:rpcMessage)
+function! s:rpc(rpcMessage, ...)
+	call call('rpcnotify', [s:serenadejobid, a:rpcMessage] + a:000)
+endfunction
+
+" Send an RPC request to the remote process and return its answer.
+function! s:request(rpcMessage, ...)
+	return call('rpcrequest', [s:serenadejobid, a:rpcMessage] + a:000)
 endfunction
 
 call s:init()
@@ -0,0 +1,391 @@
+use crate::error::BridgeError;
+use crate::neovim::Nvim;
+use log::{debug, error, warn};
+use nvim_rs::Value;
+use std::env;
+use std::str::FromStr;
+
+pub const DEFAULT_ENDPOINT: &str = "ws://localhost:17373";
+
+/// UI clients that are Neovim itself rather than a GUI, like the TUI of Neovim 0.10 and later.
+const BUILTIN_UIS: [&str; 1] = ["nvim-tui"];
+
+/// Options passed to the binary on the command line.
+#[derive(Debug, Clone, Default)]
+pub struct Args {
+    pub endpoint: Option<String>,
+    /// Neovim listen address to attach to instead of talking over stdio.
+    pub server: Option<String>,
+    /// Run as a daemon serving every Neovim that registers with it.
+    pub daemon: bool,
+    /// Neovim listen address to hand to the daemon.
+    pub register: Option<String>,
+    /// Unix socket the daemon accepts registrations on.
+    pub socket: Option<String>,
+}
+
+impl Args {
+    pub fn parse() -> Args {
+        let mut args = Args::default();
+        let mut argv = env::args().skip(1);
+
+        while let Some(arg) = argv.next() {
+            if arg == "--endpoint" {
+                args.endpoint = argv.next();
+            } else if let Some(v) = arg.strip_prefix("--endpoint=") {
+                args.endpoint = Some(v.to_string());
+            } else if arg == "--server" {
+                args.server = argv.next();
+            } else if let Some(v) = arg.strip_prefix("--server=") {
+                args.server = Some(v.to_string());
+            } else if arg == "--daemon" {
+                args.daemon = true;
+            } else if arg == "--register" {
+                args.register = argv.next();
+            } else if let Some(v) = arg.strip_prefix("--register=") {
+                args.register = Some(v.to_string());
+            } else if arg == "--socket" {
+                args.socket = argv.next();
+            } else if let Some(v) = arg.strip_prefix("--socket=") {
+                args.socket = Some(v.to_string());
+            }
+        }
+
+        return args;
+    }
+}
+
+/// What to do with a diff that goes over the configured safety limits.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiffLimitAction {
+    Refuse,
+    Confirm,
+}
+
+impl FromStr for DiffLimitAction {
+    type Err = BridgeError;
+
+    fn from_str(action: &str) -> Result<Self, BridgeError> {
+        match action {
+            "refuse" => Ok(DiffLimitAction::Refuse),
+            "confirm" => Ok(DiffLimitAction::Confirm),
+            _ => Err(BridgeError::Config(format!(
+                "g:serenade_diff_limit_action must be \"refuse\" or \"confirm\", not {:?}",
+                action
+            ))),
+        }
+    }
+}
+
+/// Settings read from the `g:serenade_*` globals when the bridge starts.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Most lines a single diff may delete, 0 disables the check.
+    pub diff_max_deleted_lines: u64,
+    /// Most of the buffer, in percent, a single diff may change, 0 disables the check.
+    pub diff_max_changed_percent: u64,
+    pub diff_limit_action: DiffLimitAction,
+    /// How many pre-edit snapshots to keep for `:SerenadeRevert`.
+    pub snapshot_limit: u64,
+    /// Rewrite the indentation of dictated lines to the buffer's style.
+    pub normalize_indent: bool,
+    /// Voice cursor moves longer than this many lines add a jumplist entry.
+    pub jump_threshold: u64,
+    /// Serenade websocket address, see `connection::connect`.
+    pub endpoint: String,
+    /// Upper bound, in seconds, of the delay between reconnect attempts.
+    pub reconnect_max_delay: u64,
+    /// Don't connect to Serenade until the first `:SerenadeStart`.
+    pub lazy_connect: bool,
+    /// Seconds between heartbeats.
+    pub heartbeat_interval: u64,
+    /// App name reported to Serenade.
+    pub app_name: String,
+    /// Text Serenade looks for in the focused window title to route commands here.
+    pub match_term: String,
+    /// Icon shown by Serenade, as a data URL.
+    pub icon: Option<String>,
+    /// Add a per-instance marker to `titlestring` and send it as the match term.
+    pub manage_title: bool,
+    /// Don't run editing commands while Neovim doesn't have focus.
+    pub pause_unfocused: bool,
+    /// Derive the instance id from `v:servername` instead of picking a random one.
+    pub stable_id: bool,
+    /// Undo a whole batch of commands when one of them fails.
+    pub transactional_batches: bool,
+    /// Worker restarts allowed within `restart_window` before the bridge gives up.
+    pub max_restarts: u64,
+    /// Seconds over which `max_restarts` is counted.
+    pub restart_window: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            diff_max_deleted_lines: 0,
+            diff_max_changed_percent: 0,
+            diff_limit_action: DiffLimitAction::Refuse,
+            snapshot_limit: 20,
+            normalize_indent: false,
+            jump_threshold: 5,
+            endpoint: DEFAULT_ENDPOINT.to_string(),
+            reconnect_max_delay: 30,
+            lazy_connect: false,
+            heartbeat_interval: 60,
+            app_name: String::from("nvim"),
+            match_term: String::from("terminal"),
+            icon: None,
+            manage_title: false,
+            pause_unfocused: false,
+            stable_id: false,
+            transactional_batches: false,
+            max_restarts: 5,
+            restart_window: 60,
+        }
+    }
+}
+
+impl Config {
+    /// Settings that don't need a Neovim instance, as used by the daemon.
+    pub fn from_args(args: &Args) -> Config {
+        let mut config = Config::default();
+
+        if let Some(v) = env::var("NVIM_SERENADE_ENDPOINT")
+            .ok()
+            .or_else(|| args.endpoint.clone())
+        {
+            config.endpoint = v;
+        }
+
+        return config;
+    }
+
+    pub async fn load(nvim: &Nvim, args: &Args) -> Config {
+        return Config::from_args(args).read_globals(nvim).await;
+    }
+
+    /// Reads the globals again, keeping the endpoint picked at startup unless
+    /// `g:serenade_endpoint` is set.
+    pub async fn reload(&self, nvim: &Nvim) -> Config {
+        let config = Config {
+            endpoint: self.endpoint.clone(),
+            ..Config::default()
+        };
+
+        let mut config = config.read_globals(nvim).await;
+
+        // The title marker belongs to the instance, not to the globals.
+        if self.manage_title {
+            config.match_term = self.match_term.clone();
+        }
+
+        return config;
+    }
+
+    async fn read_globals(self, nvim: &Nvim) -> Config {
+        let mut config = self;
+
+        if let Some(v) = get_u64(nvim, "serenade_diff_max_deleted_lines").await {
+            config.diff_max_deleted_lines = v;
+        }
+        if let Some(v) = get_u64(nvim, "serenade_diff_max_changed_percent").await {
+            config.diff_max_changed_percent = v;
+        }
+        if let Some(v) = get_string(nvim, "serenade_diff_limit_action").await {
+            match v.parse() {
+                Ok(action) => config.diff_limit_action = action,
+                Err(e) => report(nvim, e).await,
+            }
+        }
+        if let Some(v) = get_u64(nvim, "serenade_snapshot_limit").await {
+            config.snapshot_limit = v;
+        }
+        if let Some(v) = get_bool(nvim, "serenade_normalize_indent").await {
+            config.normalize_indent = v;
+        }
+        if let Some(v) = get_u64(nvim, "serenade_jump_threshold").await {
+            config.jump_threshold = v;
+        }
+        if let Some(v) = get_u64(nvim, "serenade_reconnect_max_delay").await {
+            config.reconnect_max_delay = v;
+        }
+        if let Some(v) = get_bool(nvim, "serenade_lazy_connect").await {
+            config.lazy_connect = v;
+        }
+        if let Some(v) = get_u64(nvim, "serenade_heartbeat_interval").await {
+            config.heartbeat_interval = v;
+        }
+        if let Some(v) = get_string(nvim, "serenade_app").await {
+            config.app_name = v;
+        }
+        config.match_term = match get_string(nvim, "serenade_match").await {
+            Some(v) => v,
+            None => detect_match_term(nvim).await,
+        };
+        config.icon = get_string(nvim, "serenade_icon").await;
+        if let Some(v) = get_bool(nvim, "serenade_manage_title").await {
+            config.manage_title = v;
+        }
+        if let Some(v) = get_bool(nvim, "serenade_pause_unfocused").await {
+            config.pause_unfocused = v;
+        }
+        if let Some(v) = get_bool(nvim, "serenade_stable_id").await {
+            config.stable_id = v;
+        }
+        if let Some(v) = get_bool(nvim, "serenade_transactional_batches").await {
+            config.transactional_batches = v;
+        }
+        if let Some(v) = get_u64(nvim, "serenade_max_restarts").await {
+            config.max_restarts = v;
+        }
+        if let Some(v) = get_u64(nvim, "serenade_restart_window").await {
+            config.restart_window = v;
+        }
+
+        // The Neovim global wins over the environment and command line, see `from_args`.
+        if let Some(v) = get_string(nvim, "serenade_endpoint").await {
+            config.endpoint = v;
+        }
+        if let Err(e) = check_endpoint(&config.endpoint) {
+            report(nvim, e).await;
+        }
+
+        debug!("Loaded config: {:?}", config);
+
+        return config;
+    }
+}
+
+/// Endpoints `connection::connect` knows how to reach.
+fn check_endpoint(endpoint: &str) -> Result<(), BridgeError> {
+    let schemes = ["ws://", "wss://", "unix://", "/"];
+
+    if schemes.iter().any(|v| endpoint.starts_with(v)) {
+        return Ok(());
+    }
+
+    return Err(BridgeError::Config(format!(
+        "{:?} is not a ws://, wss:// or unix:// endpoint",
+        endpoint
+    )));
+}
+
+/// Invalid settings fall back to their defaults, but the user should know about them.
+async fn report(nvim: &Nvim, e: BridgeError) {
+    error!("{}", e);
+
+    if let Err(e) = nvim.err_writeln(&format!("serenade: {}", e)).await {
+        warn!("Unable to show configuration error: {:?}", e);
+    }
+}
+
+async fn get_global(nvim: &Nvim, name: &str) -> Option<Value> {
+    return nvim.get_var(name).await.ok();
+}
+
+async fn get_u64(nvim: &Nvim, name: &str) -> Option<u64> {
+    return get_global(nvim, name).await.and_then(|v| v.as_u64());
+}
+
+async fn get_string(nvim: &Nvim, name: &str) -> Option<String> {
+    return get_global(nvim, name).await.and_then(|v| v.as_str().map(String::from));
+}
+
+async fn get_bool(nvim: &Nvim, name: &str) -> Option<bool> {
+    return get_global(nvim, name).await.and_then(|v| match v {
+        Value::Boolean(b) => Some(b),
+        v => v.as_i64().map(|i| i != 0),
+    });
+}
+
+/// Guesses which window Neovim runs in: an attached GUI first, then the terminal emulator.
+async fn detect_match_term(nvim: &Nvim) -> String {
+    if let Some(gui) = attached_gui(nvim).await {
+        return gui;
+    }
+
+    if let Ok(program) = env::var("TERM_PROGRAM") {
+        return match &program[..] {
+            "iTerm.app" => String::from("iterm"),
+            "Apple_Terminal" => String::from("terminal"),
+            "vscode" => String::from("code"),
+            _ => program.to_lowercase(),
+        };
+    }
+
+    let terminals = [
+        ("KITTY_WINDOW_ID", "kitty"),
+        ("ALACRITTY_SOCKET", "alacritty"),
+        ("ALACRITTY_LOG", "alacritty"),
+        ("WEZTERM_PANE", "wezterm"),
+        ("TILIX_ID", "tilix"),
+        ("KONSOLE_VERSION", "konsole"),
+        ("TERMINATOR_UUID", "terminator"),
+        ("GNOME_TERMINAL_SCREEN", "terminal"),
+    ];
+
+    for (var, name) in terminals.iter() {
+        if env::var_os(var).is_some() {
+            return name.to_string();
+        }
+    }
+
+    return Config::default().match_term;
+}
+
+/// Name of a UI client that announced itself with `nvim_set_client_info`, such as Neovide.
+async fn attached_gui(nvim: &Nvim) -> Option<String> {
+    let chans = nvim.list_chans().await.ok()?;
+
+    return chans.iter().find_map(gui_name);
+}
+
+/// The name of a channel's client, if it is a GUI. Neovim's own TUI doesn't count, the terminal
+/// it runs in is found from the environment instead.
+fn gui_name(chan: &Value) -> Option<String> {
+    let client = map_get(chan, "client")?;
+
+    if map_get(client, "type").and_then(|v| v.as_str()) != Some("ui") {
+        return None;
+    }
+
+    let name = map_get(client, "name").and_then(|v| v.as_str())?;
+    if BUILTIN_UIS.contains(&name) {
+        return None;
+    }
+
+    return Some(name.to_lowercase());
+}
+
+fn map_get<'a>(dict: &'a Value, key: &str) -> Option<&'a Value> {
+    return dict
+        .as_map()?
+        .iter()
+        .find(|(k, _)| k.as_str() == Some(key))
+        .map(|(_, v)| v);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chan(client_type: &str, name: &str) -> Value {
+        let client = vec![
+            (Value::from("type"), Value::from(client_type)),
+            (Value::from("name"), Value::from(name)),
+        ];
+
+        return Value::Map(vec![(Value::from("client"), Value::Map(client))]);
+    }
+
+    #[test]
+    fn gui_name_takes_ui_clients() {
+        assert_eq!(gui_name(&chan("ui", "Neovide")), Some(String::from("neovide")));
+        assert_eq!(gui_name(&chan("remote", "neovim-serenade")), None);
+    }
+
+    #[test]
+    fn gui_name_skips_the_builtin_tui() {
+        assert_eq!(gui_name(&chan("ui", "nvim-tui")), None);
+    }
+}
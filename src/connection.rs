@@ -0,0 +1,45 @@
+use futures::{Sink, Stream, StreamExt};
+use std::io;
+use std::pin::Pin;
+use tokio::net::UnixStream;
+use tokio_tungstenite::tungstenite::error::UrlError;
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::{client_async, connect_async};
+use url::Url;
+
+/// Messages read from Serenade, until the connection fails.
+pub type Reader = Pin<Box<dyn Stream<Item = Result<Message, Error>> + Send>>;
+
+/// Messages written to Serenade.
+pub type Writer = Pin<Box<dyn Sink<Message, Error = Error> + Send + Sync>>;
+
+/// Connects to `ws://` and `wss://` URLs, or to a Unix socket given as `unix:///path/to/socket`
+/// or a plain absolute path.
+///
+/// The connection comes back split, so reads never wait for writes.
+pub async fn connect(endpoint: &str) -> Result<(Writer, Reader), Error> {
+    if endpoint.starts_with('/') {
+        return connect_unix(endpoint).await;
+    }
+
+    let url = Url::parse(endpoint)
+        .map_err(|e| Error::Io(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+
+    match url.scheme() {
+        "ws" | "wss" => {
+            let (socket, _) = connect_async(url).await?;
+            let (writer, reader) = socket.split();
+            return Ok((Box::pin(writer), Box::pin(reader)));
+        }
+        "unix" => return connect_unix(url.path()).await,
+        _ => return Err(Error::Url(UrlError::UnsupportedUrlScheme)),
+    }
+}
+
+async fn connect_unix(path: &str) -> Result<(Writer, Reader), Error> {
+    let stream = UnixStream::connect(path).await?;
+    let (socket, _) = client_async("ws://localhost/", stream).await?;
+    let (writer, reader) = socket.split();
+
+    return Ok((Box::pin(writer), Box::pin(reader)));
+}
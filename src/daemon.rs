@@ -0,0 +1,177 @@
+use crate::config::{Args, Config};
+use crate::error::BridgeError;
+use crate::instance::Instance;
+use crate::neovim::{self, NVimEventHandler};
+use crate::serenade::{Control, Event, SerenadeEventHandler};
+use log::{error, info, warn};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::time;
+
+/// Where the daemon accepts registrations unless `--socket` says otherwise.
+pub fn socket_path(args: &Args) -> PathBuf {
+    if let Some(v) = &args.socket {
+        return PathBuf::from(v);
+    }
+
+    return match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("neovim-serenade.sock"),
+        None => {
+            let user = env::var("USER").unwrap_or_else(|_| String::from("default"));
+            env::temp_dir().join(format!("neovim-serenade-{}.sock", user))
+        }
+    };
+}
+
+/// Connects to the daemon listening on `socket`. A daemon that is just starting may have bound
+/// the socket without listening yet, so a refused connection is tried again a few times.
+async fn connect(socket: &Path) -> Option<UnixStream> {
+    for attempt in 0..3 {
+        if attempt > 0 {
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        if let Ok(v) = UnixStream::connect(socket).await {
+            return Some(v);
+        }
+    }
+
+    return None;
+}
+
+enum Bound {
+    Listening(UnixListener),
+    /// Another daemon holds the socket, connected to here.
+    Taken(UnixStream),
+}
+
+/// Binds the daemon socket, or connects to the daemon that already holds it. A socket nobody
+/// answers on is left over from a daemon that died, and is replaced.
+async fn bind(socket: &Path) -> Result<Bound, BridgeError> {
+    match UnixListener::bind(socket) {
+        Ok(v) => return Ok(Bound::Listening(v)),
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    if let Some(stream) = connect(socket).await {
+        return Ok(Bound::Taken(stream));
+    }
+
+    info!("Replacing the stale socket {:?}", socket);
+    fs::remove_file(socket)?;
+
+    return Ok(Bound::Listening(UnixListener::bind(socket)?));
+}
+
+/// Runs one Serenade connection shared by every Neovim that registers over the daemon socket.
+///
+/// With `--register`, the address is handed to a running daemon if there is one, otherwise this
+/// process becomes the daemon and exits again once its last instance is gone.
+pub async fn run(args: Args) -> Result<(), BridgeError> {
+    let socket = socket_path(&args);
+
+    let listener = match bind(&socket).await? {
+        Bound::Listening(v) => v,
+        Bound::Taken(mut daemon) => match &args.register {
+            Some(addr) => {
+                daemon.write_all(format!("{}\n", addr).as_bytes()).await?;
+                info!("Registered {} with the daemon at {:?}", addr, socket);
+                return Ok(());
+            }
+            None => {
+                return Err(BridgeError::Io(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("a daemon is already listening on {:?}", socket),
+                )));
+            }
+        },
+    };
+    info!("Daemon listening on {:?}", socket);
+
+    let (tx, rx) = unbounded_channel();
+    let mut serenade = SerenadeEventHandler::new(Config::from_args(&args), Vec::new(), rx);
+    let events = serenade.event_sender();
+
+    if let Some(addr) = &args.register {
+        serenade.set_exit_when_empty(true);
+        attach(addr, &args, &tx, &events).await;
+    }
+
+    let listener_args = args.clone();
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((v, _)) => v,
+                Err(e) => {
+                    warn!("Unable to accept a registration: {:?}", e);
+                    continue;
+                }
+            };
+
+            // A slow client or Neovim must not hold up the next registration.
+            let (args, tx, events) = (listener_args.clone(), tx.clone(), events.clone());
+            tokio::spawn(async move {
+                let mut addr = String::new();
+                if let Err(e) = BufReader::new(stream).read_line(&mut addr).await {
+                    warn!("Unable to read a registration: {:?}", e);
+                    return;
+                }
+
+                if !addr.trim().is_empty() {
+                    attach(addr.trim(), &args, &tx, &events).await;
+                }
+            });
+        }
+    });
+
+    serenade.handle_events().await;
+
+    let _ = fs::remove_file(&socket);
+
+    return Ok(());
+}
+
+async fn attach(
+    addr: &str,
+    args: &Args,
+    tx: &UnboundedSender<Control>,
+    events: &UnboundedSender<Event>,
+) {
+    info!("Attaching to {}", addr);
+
+    let (handler, mut notifications) = neovim::handler();
+    let nvim = match neovim::connect(Some(addr), handler).await {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Unable to connect to Neovim at {}: {:?}", addr, e);
+            return;
+        }
+    };
+
+    if let Err(e) = neovim::register_commands(&nvim).await {
+        error!("Unable to register Serenade commands in {}: {:?}", addr, e);
+        return;
+    }
+
+    let instance = Instance::new(nvim.clone(), args).await;
+    let mut handler = NVimEventHandler::new(
+        nvim,
+        Arc::clone(&instance.history),
+        instance.id.clone(),
+        true,
+        tx.clone(),
+    );
+
+    tokio::spawn(async move { handler.handle_events(&mut notifications).await });
+
+    if events.send(Event::Attach(instance)).is_err() {
+        error!("The Serenade thread is gone, dropping {}", addr);
+    }
+}
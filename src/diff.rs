@@ -0,0 +1,164 @@
+use std::cmp;
+
+/// Above this many old times new lines, the changed middle counts as replaced as a whole instead
+/// of being compared line by line.
+const MAX_DIFF_CELLS: usize = 1 << 22;
+
+/// Line-level summary of how one version of a buffer turns into another.
+///
+/// Lines are matched along a longest common subsequence, so an edit at the top and another one at
+/// the bottom only count the lines they touch.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LineChange {
+    pub deleted: usize,
+    pub inserted: usize,
+    /// Indices of the lines of the new version that aren't in the old one, in order.
+    pub inserted_lines: Vec<usize>,
+}
+
+impl LineChange {
+    pub fn between(old: &[String], new: &[String]) -> LineChange {
+        let prefix = old
+            .iter()
+            .zip(new.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        let max_suffix = cmp::min(old.len(), new.len()) - prefix;
+        let suffix = old
+            .iter()
+            .rev()
+            .zip(new.iter().rev())
+            .take(max_suffix)
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        let old = &old[prefix..old.len() - suffix];
+        let new = &new[prefix..new.len() - suffix];
+        let kept = if old.len() * new.len() > MAX_DIFF_CELLS {
+            vec![false; new.len()]
+        } else {
+            common_lines(old, new)
+        };
+        let common = kept.iter().filter(|v| **v).count();
+
+        return LineChange {
+            deleted: old.len() - common,
+            inserted: new.len() - common,
+            inserted_lines: kept
+                .iter()
+                .enumerate()
+                .filter(|(_, v)| !**v)
+                .map(|(i, _)| prefix + i)
+                .collect(),
+        };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.deleted == 0 && self.inserted == 0;
+    }
+
+    /// Share of the old buffer touched by the change, in percent.
+    pub fn changed_percent(&self, old_len: usize) -> u64 {
+        if old_len == 0 {
+            return if self.is_empty() { 0 } else { 100 };
+        }
+
+        return (cmp::max(self.deleted, self.inserted) * 100 / old_len) as u64;
+    }
+}
+
+/// Marks the lines of `new` that belong to a longest common subsequence with `old`.
+///
+/// The lengths fit in `u16`, since `MAX_DIFF_CELLS` keeps the shorter side below 2048 lines.
+fn common_lines(old: &[String], new: &[String]) -> Vec<bool> {
+    let width = new.len() + 1;
+    // `lengths[i * width + j]` is the length of the longest common subsequence of `old[i..]` and
+    // `new[j..]`.
+    let mut lengths = vec![0u16; (old.len() + 1) * width];
+
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i * width + j] = if old[i] == new[j] {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                cmp::max(lengths[(i + 1) * width + j], lengths[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut kept = vec![false; new.len()];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            kept[j] = true;
+            i += 1;
+            j += 1;
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+
+    return kept;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        return text.chars().map(|c| c.to_string()).collect();
+    }
+
+    #[test]
+    fn counts_only_changed_lines() {
+        let change = LineChange::between(&lines("abcde"), &lines("XbcdY"));
+
+        assert_eq!(change.deleted, 2);
+        assert_eq!(change.inserted, 2);
+        assert_eq!(change.inserted_lines, vec![0, 4]);
+    }
+
+    #[test]
+    fn separate_insertions_delete_nothing() {
+        let change = LineChange::between(&lines("abcd"), &lines("aXbcdY"));
+
+        assert_eq!(change.deleted, 0);
+        assert_eq!(change.inserted, 2);
+        assert_eq!(change.inserted_lines, vec![1, 5]);
+    }
+
+    #[test]
+    fn finds_moved_and_deleted_lines() {
+        let change = LineChange::between(&lines("abcdef"), &lines("acdbf"));
+
+        assert_eq!(change.deleted, 2);
+        assert_eq!(change.inserted, 1);
+        assert_eq!(change.inserted_lines, vec![3]);
+    }
+
+    #[test]
+    fn identical_buffers_are_empty() {
+        let change = LineChange::between(&lines("abc"), &lines("abc"));
+
+        assert!(change.is_empty());
+        assert_eq!(change.changed_percent(3), 0);
+    }
+
+    #[test]
+    fn changed_percent_uses_the_larger_side() {
+        let change = LineChange::between(&lines("abcde"), &lines("XbcdY"));
+        assert_eq!(change.changed_percent(5), 40);
+
+        let change = LineChange::between(&lines("abcd"), &lines("abcdXYZW"));
+        assert_eq!(change.changed_percent(4), 100);
+    }
+
+    #[test]
+    fn changed_percent_of_an_empty_buffer() {
+        assert_eq!(LineChange::between(&[], &[]).changed_percent(0), 0);
+        assert_eq!(LineChange::between(&[], &lines("a")).changed_percent(0), 100);
+    }
+}
@@ -0,0 +1,75 @@
+use nvim_rs::error::CallError;
+use std::fmt;
+use std::io;
+use tokio_tungstenite::tungstenite;
+
+/// Everything that can go wrong while bridging Neovim and Serenade.
+#[derive(Debug)]
+pub enum BridgeError {
+    /// A Neovim RPC call failed.
+    Rpc(Box<CallError>),
+    /// A lock was poisoned by a thread that panicked while holding it.
+    Lock(&'static str),
+    Websocket(Box<tungstenite::Error>),
+    /// Serenade sent something that can't be acted on.
+    Protocol(String),
+    /// A `g:serenade_*` global or command line option has an invalid value.
+    Config(String),
+    Io(io::Error),
+}
+
+impl BridgeError {
+    /// Whether the user should see the error in Neovim, and not only in the log.
+    pub fn is_user_relevant(&self) -> bool {
+        match self {
+            BridgeError::Rpc(e) => match **e {
+                CallError::NeovimError(_, _) => return true,
+                _ => return false,
+            },
+            BridgeError::Config(_) => return true,
+            _ => return false,
+        }
+    }
+}
+
+impl fmt::Display for BridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BridgeError::Rpc(e) => match &**e {
+                CallError::NeovimError(_, v) => write!(f, "{}", v),
+                e => write!(f, "{}", e),
+            },
+            BridgeError::Lock(what) => write!(f, "Unable to take the lock for \"{}\"", what),
+            BridgeError::Websocket(e) => write!(f, "websocket error: {}", e),
+            BridgeError::Protocol(v) => write!(f, "protocol error: {}", v),
+            BridgeError::Config(v) => write!(f, "invalid configuration: {}", v),
+            BridgeError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for BridgeError {}
+
+impl From<Box<CallError>> for BridgeError {
+    fn from(e: Box<CallError>) -> Self {
+        return BridgeError::Rpc(e);
+    }
+}
+
+impl From<tungstenite::Error> for BridgeError {
+    fn from(e: tungstenite::Error) -> Self {
+        return BridgeError::Websocket(Box::new(e));
+    }
+}
+
+impl From<serde_json::Error> for BridgeError {
+    fn from(e: serde_json::Error) -> Self {
+        return BridgeError::Protocol(e.to_string());
+    }
+}
+
+impl From<io::Error> for BridgeError {
+    fn from(e: io::Error) -> Self {
+        return BridgeError::Io(e);
+    }
+}
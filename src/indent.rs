@@ -0,0 +1,264 @@
+use crate::neovim::Buffer;
+use log::debug;
+use nvim_rs::error::CallError;
+use std::fs;
+use std::path::Path;
+
+/// Tabs-or-spaces style that dictated lines are rewritten to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndentStyle {
+    pub expandtab: bool,
+    pub tabstop: usize,
+    /// Columns per indentation level, `0` to follow `tabstop` like Vim does.
+    pub shiftwidth: usize,
+}
+
+impl IndentStyle {
+    /// Reads `expandtab`, `tabstop` and `shiftwidth` from the buffer, letting a matching
+    /// `.editorconfig` override them.
+    pub async fn for_buffer(buffer: &Buffer) -> Result<IndentStyle, Box<CallError>> {
+        let mut style = IndentStyle {
+            expandtab: buffer.get_option("expandtab").await?.as_bool().unwrap_or(false),
+            tabstop: buffer.get_option("tabstop").await?.as_u64().unwrap_or(8) as usize,
+            shiftwidth: buffer.get_option("shiftwidth").await?.as_u64().unwrap_or(0) as usize,
+        };
+
+        let name = buffer.get_name().await?;
+        if !name.is_empty() {
+            apply_editorconfig(Path::new(&name), &mut style);
+        }
+        if style.tabstop == 0 {
+            style.tabstop = 8;
+        }
+
+        debug!("Indent style for {:?}: {:?}", name, style);
+
+        return Ok(style);
+    }
+
+    /// Rewrites the leading whitespace of `line`. A tab in dictated code is one indentation level,
+    /// so it counts as `shiftwidth` columns, and the result uses tabs of `tabstop` columns.
+    ///
+    /// Returns the new line and the byte lengths of the old and new indentation.
+    pub fn reindent(&self, line: &str) -> (String, usize, usize) {
+        let rest = line.trim_start_matches([' ', '\t']);
+        let old_len = line.len() - rest.len();
+        let level = if self.shiftwidth == 0 { self.tabstop } else { self.shiftwidth };
+
+        let mut width = 0;
+        for chr in line[..old_len].chars() {
+            if chr == '\t' {
+                width += level - width % level;
+            } else {
+                width += 1;
+            }
+        }
+
+        let indent = if self.expandtab {
+            " ".repeat(width)
+        } else {
+            "\t".repeat(width / self.tabstop) + &" ".repeat(width % self.tabstop)
+        };
+        let new_len = indent.len();
+
+        return (indent + rest, old_len, new_len);
+    }
+}
+
+/// Applies `.editorconfig` files from the root down, so the closest file wins.
+fn apply_editorconfig(path: &Path, style: &mut IndentStyle) {
+    let mut files = Vec::new();
+    let mut dir = path.parent();
+
+    while let Some(d) = dir {
+        let candidate = d.join(".editorconfig");
+        if let Ok(contents) = fs::read_to_string(&candidate) {
+            let is_root = contents.lines().any(|l| {
+                let l = l.trim().to_lowercase().replace(' ', "");
+                l == "root=true"
+            });
+            files.push((d.to_path_buf(), contents));
+            if is_root {
+                break;
+            }
+        }
+        dir = d.parent();
+    }
+
+    for (dir, contents) in files.iter().rev() {
+        let relative = match path.strip_prefix(dir) {
+            Ok(v) => v.to_string_lossy().to_string(),
+            _ => continue,
+        };
+        apply_editorconfig_file(contents, &relative, style);
+    }
+}
+
+fn apply_editorconfig_file(contents: &str, relative: &str, style: &mut IndentStyle) {
+    let file_name = relative.rsplit('/').next().unwrap_or(relative);
+    let mut in_section = false;
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            let pattern = &line[1..line.len() - 1];
+            in_section = if pattern.contains('/') {
+                glob_match(pattern.trim_start_matches('/'), relative)
+            } else {
+                glob_match(pattern, file_name)
+            };
+            continue;
+        }
+
+        if !in_section {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap_or("").trim().to_lowercase();
+        let value = parts.next().unwrap_or("").trim().to_lowercase();
+
+        match &key[..] {
+            "indent_style" => style.expandtab = value == "space",
+            // `tab` means one level per tab, which `shiftwidth` 0 says as well.
+            "indent_size" if value == "tab" => style.shiftwidth = 0,
+            "indent_size" => {
+                if let Ok(v) = value.parse::<usize>() {
+                    style.shiftwidth = v;
+                }
+            }
+            "tab_width" => {
+                if let Ok(v) = value.parse::<usize>() {
+                    style.tabstop = v;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Matches the `*`, `**`, `?` and `{a,b}` subset of editorconfig globs.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    if let Some(open) = pattern.find('{') {
+        if let Some(close) = pattern[open..].find('}') {
+            let close = open + close;
+            return pattern[open + 1..close].split(',').any(|alt| {
+                let expanded = format!("{}{}{}", &pattern[..open], alt, &pattern[close + 1..]);
+                glob_match(&expanded, text)
+            });
+        }
+    }
+
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    return glob_match_chars(&pattern, &text);
+}
+
+fn glob_match_chars(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            (0..=text.len()).any(|i| glob_match_chars(&pattern[2..], &text[i..]))
+        }
+        Some('*') => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != '/')
+            .any(|i| glob_match_chars(&pattern[1..], &text[i..])),
+        Some('?') => {
+            !text.is_empty() && text[0] != '/' && glob_match_chars(&pattern[1..], &text[1..])
+        }
+        Some(c) => !text.is_empty() && text[0] == *c && glob_match_chars(&pattern[1..], &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn style(expandtab: bool, tabstop: usize, shiftwidth: usize) -> IndentStyle {
+        return IndentStyle {
+            expandtab,
+            tabstop,
+            shiftwidth,
+        };
+    }
+
+    #[test]
+    fn reindent_expands_tabs_by_shiftwidth() {
+        let (line, old_len, new_len) = style(true, 8, 4).reindent("\t\tfoo();");
+
+        assert_eq!(line, "        foo();");
+        assert_eq!((old_len, new_len), (2, 8));
+    }
+
+    #[test]
+    fn reindent_fills_with_tabs_and_spaces() {
+        assert_eq!(style(false, 8, 4).reindent("            foo();").0, "\t    foo();");
+        assert_eq!(style(false, 4, 0).reindent("\t  foo();").0, "\t  foo();");
+    }
+
+    #[test]
+    fn reindent_keeps_unindented_lines() {
+        assert_eq!(style(true, 4, 4).reindent("foo();"), (String::from("foo();"), 0, 0));
+        assert_eq!(style(true, 4, 4).reindent("").0, "");
+    }
+
+    #[test]
+    fn glob_matches_editorconfig_patterns() {
+        assert!(glob_match("*", "main.rs"));
+        assert!(glob_match("*.{rs,toml}", "Cargo.toml"));
+        assert!(!glob_match("*.{rs,toml}", "README.md"));
+        assert!(glob_match("src/*.rs", "src/main.rs"));
+        assert!(!glob_match("src/*.rs", "src/bin/main.rs"));
+        assert!(glob_match("src/**.rs", "src/bin/main.rs"));
+        assert!(glob_match("?.c", "a.c"));
+        assert!(!glob_match("?.c", "ab.c"));
+    }
+
+    #[test]
+    fn editorconfig_sets_shiftwidth_from_indent_size() {
+        let mut result = style(false, 8, 8);
+        let contents = "[*]\nindent_style = space\nindent_size = 2\n";
+        apply_editorconfig_file(contents, "main.rs", &mut result);
+
+        assert_eq!(result, style(true, 8, 2));
+    }
+
+    #[test]
+    fn editorconfig_tab_width_and_indent_size_tab() {
+        let mut result = style(true, 8, 4);
+        let contents = "[*.go]\nindent_style = tab\nindent_size = tab\ntab_width = 4\n";
+        apply_editorconfig_file(contents, "main.go", &mut result);
+
+        assert_eq!(result, style(false, 4, 0));
+    }
+
+    #[test]
+    fn editorconfig_applies_matching_sections_in_order() {
+        let contents = "\
+            root = true\n\
+            # defaults\n\
+            [*]\n\
+            indent_size = 4\n\
+            [*.md]\n\
+            indent_size = 2\n\
+            [lib/**.rs]\n\
+            indent_size = 3\n";
+
+        let mut result = style(true, 8, 8);
+        apply_editorconfig_file(contents, "src/main.rs", &mut result);
+        assert_eq!(result.shiftwidth, 4);
+
+        let mut result = style(true, 8, 8);
+        apply_editorconfig_file(contents, "docs/README.md", &mut result);
+        assert_eq!(result.shiftwidth, 2);
+
+        let mut result = style(true, 8, 8);
+        apply_editorconfig_file(contents, "lib/a/b.rs", &mut result);
+        assert_eq!(result.shiftwidth, 3);
+    }
+}
@@ -0,0 +1,135 @@
+use crate::config::{Args, Config};
+use crate::neovim::Nvim;
+use crate::schedule::Scheduler;
+use crate::snapshot::SnapshotHistory;
+use log::{error, warn};
+use nvim_rs::error::CallError;
+use nvim_rs::Value;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// A Neovim instance the bridge edits on Serenade's behalf, with the state kept for it.
+#[derive(Clone)]
+pub struct Instance {
+    pub id: String,
+    pub nvim: Nvim,
+    pub namespace: Option<i64>,
+    pub config: Config,
+    pub history: Arc<Mutex<SnapshotHistory>>,
+    /// Cleared on `FocusLost`. Starts out set, since not every terminal reports focus.
+    pub focused: bool,
+    /// Orders the Serenade requests running in this instance.
+    pub scheduler: Scheduler,
+}
+
+impl Instance {
+    pub async fn new(nvim: Nvim, args: &Args) -> Instance {
+        return Instance::build(nvim, args, None, None).await;
+    }
+
+    /// Rebuilds an instance whose worker was restarted, keeping its id and snapshots.
+    pub async fn resume(
+        nvim: Nvim,
+        args: &Args,
+        id: &str,
+        history: Arc<Mutex<SnapshotHistory>>,
+    ) -> Instance {
+        return Instance::build(nvim, args, Uuid::parse_str(id).ok(), Some(history)).await;
+    }
+
+    async fn build(
+        nvim: Nvim,
+        args: &Args,
+        known_id: Option<Uuid>,
+        history: Option<Arc<Mutex<SnapshotHistory>>>,
+    ) -> Instance {
+        let mut id = known_id.unwrap_or_else(Uuid::new_v4);
+
+        // Namespaces are looked up by name, so a rebuilt instance gets the same one back.
+        let namespace = match nvim.create_namespace("Serenade").await {
+            Ok(v) => Some(v),
+            Err(e) => {
+                warn!("Unable to create the Serenade namespace: {:?}", e);
+                None
+            }
+        };
+        let mut config = Config::load(&nvim, args).await;
+
+        if config.stable_id && known_id.is_none() {
+            id = Instance::stable_id(&nvim).await.unwrap_or(id);
+        }
+
+        if config.manage_title {
+            let marker = format!("serenade-{}", &id.to_string()[..8]);
+            match Instance::manage_title(&nvim, &marker).await {
+                Ok(_) => config.match_term = marker,
+                Err(e) => warn!("Unable to set window title: {:?}", e),
+            }
+        }
+
+        let history = match history {
+            Some(v) => {
+                match v.lock() {
+                    Ok(mut history) => history.set_capacity(config.snapshot_limit as usize),
+                    _ => error!("Unable to lock snapshot history"),
+                }
+                v
+            }
+            None => Arc::new(Mutex::new(SnapshotHistory::new(config.snapshot_limit as usize))),
+        };
+
+        return Instance {
+            id: id.to_string(),
+            nvim,
+            namespace,
+            config,
+            history,
+            focused: true,
+            scheduler: Scheduler::default(),
+        };
+    }
+
+    /// Derives the id from the Neovim server address, so it survives bridge restarts.
+    async fn stable_id(nvim: &Nvim) -> Option<Uuid> {
+        let servername = nvim.get_vvar("servername").await.ok()?;
+        let servername = servername.as_str().filter(|v| !v.is_empty())?;
+
+        return Some(Uuid::new_v5(&Uuid::NAMESPACE_URL, servername.as_bytes()));
+    }
+
+    /// Turns on `title` and appends `marker` to `titlestring`, so Serenade can tell which
+    /// window belongs to this instance. An `OptionSet` autocmd puts the marker back whenever a
+    /// colorscheme, plugin or the user changes either option later.
+    async fn manage_title(nvim: &Nvim, marker: &str) -> Result<(), Box<CallError>> {
+        nvim.exec_lua(
+            r#"
+            local marker = ...
+            local function apply()
+                -- Setting an option fires OptionSet even when the value stays the same.
+                if not vim.o.title then
+                    vim.o.title = true
+                end
+                local titlestring = vim.o.titlestring
+                if not titlestring:find(marker, 1, true) then
+                    if titlestring == "" then
+                        titlestring = "%t - NVIM"
+                    end
+                    vim.o.titlestring = titlestring .. " [" .. marker .. "]"
+                end
+            end
+
+            apply()
+            local group = vim.api.nvim_create_augroup("serenade_title", { clear = true })
+            vim.api.nvim_create_autocmd("OptionSet", {
+                group = group,
+                pattern = { "title", "titlestring" },
+                callback = function() vim.schedule(apply) end,
+            })
+            "#,
+            vec![Value::from(marker)],
+        )
+        .await?;
+
+        return Ok(());
+    }
+}
@@ -1,10 +1,23 @@
-#[macro_use]
-extern crate unwrap;
-
+#![allow(clippy::needless_return)]
+
+mod config;
+mod connection;
+mod daemon;
+mod diff;
+mod error;
+mod indent;
+mod instance;
 pub mod neovim;
+mod protocol;
+mod schedule;
 mod serenade;
+mod snapshot;
+mod supervisor;
+mod transaction;
+mod view;
 
-use log::{debug, LevelFilter};
+use error::BridgeError;
+use log::{error, LevelFilter};
 use log4rs::{
     append::{
         console::{ConsoleAppender, Target},
@@ -15,37 +28,26 @@ use log4rs::{
     filter::threshold::ThresholdFilter,
 };
 use std::env;
-use std::sync::mpsc::channel;
-use std::{thread, time};
-
-use neovim_lib::{Neovim, Session};
-use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{Arc, Mutex};
-
-fn init(tx: Sender<String>, rx: Receiver<String>) {
-    let mut session = Session::new_parent().unwrap();
-    session.set_infinity_timeout();
-
-    let neovim = Neovim::new(session);
-
-    let nvim_instance = Arc::new(Mutex::new(neovim));
- 
-    let mut nvim = neovim::NVimEventHandler::new(Arc::clone(&nvim_instance), tx);
+use std::process;
 
-    let nvim_thread = thread::spawn(move || nvim.handle_events());
+async fn init(args: config::Args) -> Result<(), BridgeError> {
+    let (handler, notifications) = neovim::handler();
+    let nvim = neovim::connect(args.server.as_deref(), handler).await?;
 
-    let mut serenade = serenade::SerenadeEventHandler::new(Arc::clone(&nvim_instance), rx);
+    // The plugin defines the commands when it starts us, an attached bridge has to do it itself.
+    if args.server.is_some() {
+        neovim::register_commands(&nvim).await?;
+    }
 
-    let serenade_thread = thread::spawn(move || serenade.handle_events());
-    //let nvim_thread = thread::spawn(move || nvim.handle_events());
+    supervisor::run(nvim, notifications, args).await;
 
-    serenade_thread
-        .join()
-        .expect("The serenade thread has panicked");
-    nvim_thread.join().expect("The neovim thread has panicked");
+    return Ok(());
 }
 
-fn main() {
+#[tokio::main]
+async fn main() {
+    let args = config::Args::parse();
+
     let log_path = match env::var_os("NVIM_SERENADE_LOG_FILE") {
         Some(v) => format!("{:?}", v),
         None => "/tmp/neovim-serenade.log".to_string(),
@@ -55,12 +57,18 @@ fn main() {
     let stderr = ConsoleAppender::builder().target(Target::Stderr).build();
 
     // Logging to log file.
-    let logfile = FileAppender::builder()
+    let logfile = match FileAppender::builder()
         .encoder(Box::new(PatternEncoder::new("{l} - {m}\n")))
         .build(log_path)
-        .unwrap();
+    {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("neovim-serenade: unable to open the log file: {}", e);
+            process::exit(1);
+        }
+    };
 
-    let config = Config::builder()
+    let config = match Config::builder()
         .appender(Appender::builder().build("logfile", Box::new(logfile)))
         .appender(
             Appender::builder()
@@ -72,12 +80,24 @@ fn main() {
                 .appender("logfile")
                 .appender("stderr")
                 .build(LevelFilter::Debug),
-        )
-        .unwrap();
+        ) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("neovim-serenade: invalid log configuration: {}", e);
+            process::exit(1);
+        }
+    };
 
     let _handle = log4rs::init_config(config);
     
-    let (tx, rx) = channel();
+    let result = if args.daemon {
+        daemon::run(args).await
+    } else {
+        init(args).await
+    };
 
-    init(tx, rx);
+    if let Err(e) = result {
+        error!("{}", e);
+        process::exit(1);
+    }
 }
@@ -1,11 +1,39 @@
-use neovim_lib::{Neovim, NeovimApi};
+use crate::error::BridgeError;
+use crate::serenade::Control;
+use crate::snapshot::SnapshotHistory;
+use async_trait::async_trait;
+use log::{error, info, warn};
+use nvim_rs::error::{CallError, LoopError};
+use nvim_rs::{Handler, Neovim, Value};
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::time;
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
 
-use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 
+/// Write half of a Neovim session, boxed so stdio, Unix socket and TCP sessions share one type.
+pub type Writer = Compat<Box<dyn AsyncWrite + Send + Unpin>>;
+pub type Nvim = Neovim<Writer>;
+pub type Buffer = nvim_rs::Buffer<Writer>;
+pub type Window = nvim_rs::Window<Writer>;
+
 enum NeoVimMessages {
     SerenadeStart,
     SerenadeStop,
+    SerenadeRevert,
+    SerenadeSnapshots,
+    SerenadeShutdown,
+    SerenadeFocus,
+    SerenadeBlur,
+    SerenadeStatus,
+    SerenadeReconnect,
+    SerenadeReload,
     Unknown(String),
 }
 
@@ -14,36 +42,388 @@ impl From<String> for NeoVimMessages {
         match &event[..] {
             "serenade_start" => NeoVimMessages::SerenadeStart,
             "serenade_stop" => NeoVimMessages::SerenadeStop,
+            "serenade_revert" => NeoVimMessages::SerenadeRevert,
+            "serenade_snapshots" => NeoVimMessages::SerenadeSnapshots,
+            "serenade_shutdown" => NeoVimMessages::SerenadeShutdown,
+            "serenade_focus" => NeoVimMessages::SerenadeFocus,
+            "serenade_blur" => NeoVimMessages::SerenadeBlur,
+            "serenade_status" => NeoVimMessages::SerenadeStatus,
+            "serenade_reconnect" => NeoVimMessages::SerenadeReconnect,
+            "serenade_reload" => NeoVimMessages::SerenadeReload,
             _ => NeoVimMessages::Unknown(event),
         }
     }
 }
 
+/// Something Neovim sent over the session.
+pub enum Incoming {
+    Notification(String, Vec<Value>),
+    /// An `rpcrequest`, which Neovim blocks on until `reply` is answered.
+    Request {
+        name: String,
+        reply: oneshot::Sender<Result<Value, Value>>,
+    },
+}
+
+/// What Neovim sent, in the order it was sent, see `NVimEventHandler`.
+pub type Notifications = UnboundedReceiver<Incoming>;
+
+/// Passes everything Neovim sends on to a `NVimEventHandler`. The channel closes with the session.
+#[derive(Clone)]
+pub struct NVimHandler {
+    tx: UnboundedSender<Incoming>,
+}
+
+#[async_trait]
+impl Handler for NVimHandler {
+    type Writer = Writer;
+
+    async fn handle_notify(&self, name: String, args: Vec<Value>, _: Nvim) {
+        if self.tx.send(Incoming::Notification(name, args)).is_err() {
+            warn!("Dropping a notification, nobody handles them anymore");
+        }
+    }
+
+    async fn handle_request(&self, name: String, _args: Vec<Value>, _: Nvim) -> Result<Value, Value> {
+        let (reply, answer) = oneshot::channel();
+
+        if self.tx.send(Incoming::Request { name, reply }).is_err() {
+            return Err(Value::from("serenade: the bridge is shutting down"));
+        }
+
+        // Neovim is blocked until the answer arrives, so don't keep it waiting for long.
+        match time::timeout(Duration::from_secs(2), answer).await {
+            Ok(Ok(v)) => return v,
+            _ => return Err(Value::from("serenade: busy, try again")),
+        }
+    }
+}
+
+pub fn handler() -> (NVimHandler, Notifications) {
+    let (tx, notifications) = unbounded_channel();
+
+    return (NVimHandler { tx }, notifications);
+}
+
+/// Opens a session to a Neovim listen address, either a Unix socket path or `host:port`, or to
+/// the Neovim that started us over stdio when there is no address.
+pub async fn connect(addr: Option<&str>, handler: NVimHandler) -> io::Result<Nvim> {
+    let nvim = match addr {
+        Some(addr) if addr.contains(':') && !addr.starts_with('/') => {
+            let (reader, writer) = tokio::io::split(TcpStream::connect(addr).await?);
+            let (nvim, io) = Neovim::new(reader.compat(), boxed(writer), handler);
+            tokio::spawn(run_io(io));
+            nvim
+        }
+        Some(addr) => {
+            let (reader, writer) = tokio::io::split(UnixStream::connect(addr).await?);
+            let (nvim, io) = Neovim::new(reader.compat(), boxed(writer), handler);
+            tokio::spawn(run_io(io));
+            nvim
+        }
+        None => {
+            // Stdout is line buffered, which would hold back RPC messages.
+            let stdout = tokio::fs::File::from_std(nvim_rs::create::unbuffered_stdout()?);
+            let (nvim, io) = Neovim::new(tokio::io::stdin().compat(), boxed(stdout), handler);
+            tokio::spawn(run_io(io));
+            nvim
+        }
+    };
+
+    return Ok(nvim);
+}
+
+fn boxed<W: AsyncWrite + Send + Unpin + 'static>(writer: W) -> Writer {
+    let writer: Box<dyn AsyncWrite + Send + Unpin> = Box::new(writer);
+    return writer.compat_write();
+}
+
+/// Reads from the session until Neovim goes away.
+async fn run_io(io: impl Future<Output = Result<(), Box<LoopError>>>) {
+    match io.await {
+        Err(e) if !e.is_channel_closed() && !e.is_reader_error() => {
+            error!("Neovim session failed: {}", e)
+        }
+        _ => info!("Neovim session closed"),
+    }
+}
+
+/// Runs several API calls as one `nvim_call_atomic`, failing with the first error Neovim reports.
+pub async fn atomic(nvim: &Nvim, calls: Vec<(&str, Vec<Value>)>) -> Result<Vec<Value>, BridgeError> {
+    let calls = calls
+        .into_iter()
+        .map(|(method, args)| Value::Array(vec![Value::from(method), Value::Array(args)]))
+        .collect();
+
+    let mut response = nvim.call_atomic(calls).await?.into_iter();
+    let results = match response.next() {
+        Some(Value::Array(v)) => v,
+        _ => return Err(BridgeError::Protocol(String::from("malformed nvim_call_atomic response"))),
+    };
+
+    // The second element is nil, or `[index, type, message]` for the call that failed.
+    if let Some(Value::Array(failure)) = response.next() {
+        let message = failure.get(2).and_then(|v| v.as_str()).unwrap_or("unknown error");
+        return Err(BridgeError::Rpc(Box::new(CallError::NeovimError(None, message.to_string()))));
+    }
+
+    return Ok(results);
+}
+
+/// Defines the `:Serenade*` commands for a Neovim instance that didn't start us from the plugin.
+pub async fn register_commands(nvim: &Nvim) -> Result<(), BridgeError> {
+    let api_info = nvim.get_api_info().await?;
+    let channel = match api_info.first().and_then(|v| v.as_i64()) {
+        Some(v) => v,
+        None => return Err(BridgeError::Protocol(String::from("no channel id in api info"))),
+    };
+
+    let commands = [
+        ("-nargs=0", "SerenadeStart", "'serenade_start'"),
+        ("-nargs=0", "SerenadeStop", "'serenade_stop'"),
+        ("-nargs=?", "SerenadeRevert", "'serenade_revert', <q-args>"),
+        ("-nargs=0", "SerenadeSnapshots", "'serenade_snapshots'"),
+        ("-nargs=0", "SerenadeReconnect", "'serenade_reconnect'"),
+        ("-nargs=0", "SerenadeReload", "'serenade_reload'"),
+    ];
+
+    for (nargs, name, args) in commands.iter() {
+        nvim.command(&format!(
+            "command! {} {} call rpcnotify({}, {})",
+            nargs, name, channel, args
+        ))
+        .await?;
+    }
+
+    // Answered synchronously, see `NVimHandler::handle_request`.
+    nvim.command(&format!(
+        "command! -nargs=0 SerenadeStatus echo rpcrequest({}, 'serenade_status')",
+        channel
+    ))
+    .await?;
+
+    nvim.command(&format!(
+        "augroup serenade | autocmd! \
+         | autocmd VimLeavePre * call rpcnotify({0}, 'serenade_shutdown') \
+         | autocmd FocusGained * call rpcnotify({0}, 'serenade_focus') \
+         | autocmd FocusLost * call rpcnotify({0}, 'serenade_blur') \
+         | augroup END",
+        channel
+    ))
+    .await?;
+
+    return Ok(());
+}
+
 /// EventHandler receives RPC requests, and maps them to right Serenade and Neovim commands.
 pub struct NVimEventHandler {
-    nvim: Arc<Mutex<Neovim>>,
-    tx: Sender<String>,
+    nvim: Nvim,
+    history: Arc<Mutex<SnapshotHistory>>,
+    /// Id of the instance this handler serves, used to tag focus and detach messages.
+    id: String,
+    /// A daemon outlives the instances attached to it, so exiting only detaches this one.
+    daemon: bool,
+    tx: UnboundedSender<Control>,
 }
 
 impl NVimEventHandler {
-    pub fn new(nvim: Arc<Mutex<Neovim>>, tx: Sender<String>) -> NVimEventHandler {
-        NVimEventHandler { nvim, tx }
+    pub fn new(
+        nvim: Nvim,
+        history: Arc<Mutex<SnapshotHistory>>,
+        id: String,
+        daemon: bool,
+        tx: UnboundedSender<Control>,
+    ) -> NVimEventHandler {
+        NVimEventHandler { nvim, history, id, daemon, tx }
     }
 
-    pub fn handle_events(&mut self) {
-        let receiver = self.nvim.lock().unwrap().session.start_event_loop_channel();
-        for (event, _values) in receiver {
+    /// Handles what Neovim sends until the session closes. The receiver outlives this handler, so
+    /// a supervisor can start a new handler on the same session after a panic.
+    pub async fn handle_events(&mut self, receiver: &mut Notifications) {
+        while let Some(incoming) = receiver.recv().await {
+            let (event, values) = match incoming {
+                Incoming::Notification(event, values) => (event, values),
+                Incoming::Request { name, reply } => {
+                    self.handle_request(name, reply);
+                    continue;
+                }
+            };
+
             match NeoVimMessages::from(event) {
-                NeoVimMessages::SerenadeStart => self.tx.send("start".to_string()).unwrap(),
-                NeoVimMessages::SerenadeStop => self.tx.send("stop".to_string()).unwrap(),
+                NeoVimMessages::SerenadeStart => self.send(Control::Start),
+                NeoVimMessages::SerenadeStop => self.send(Control::Stop),
+                NeoVimMessages::SerenadeShutdown => self.exit(),
+                NeoVimMessages::SerenadeFocus => self.send(Control::Focus(self.id.clone())),
+                NeoVimMessages::SerenadeBlur => self.send(Control::Blur(self.id.clone())),
+                NeoVimMessages::SerenadeReconnect => self.send(Control::Reconnect),
+                NeoVimMessages::SerenadeReload => self.send(Control::ReloadConfig(self.id.clone())),
+                NeoVimMessages::SerenadeRevert => match NVimEventHandler::snapshot_index(&values) {
+                    Ok(n) => {
+                        if let Err(e) = self.revert(n).await {
+                            self.show_error(&format!("unable to revert snapshot {}: {}", n, e)).await;
+                        }
+                    }
+                    Err(e) => self.show_error(&e).await,
+                },
+                NeoVimMessages::SerenadeSnapshots => {
+                    if let Err(e) = self.list_snapshots().await {
+                        error!("Unable to list snapshots: {}", e);
+                    }
+                }
+                NeoVimMessages::SerenadeStatus => {
+                    warn!("serenade_status has to be sent with rpcrequest");
+                }
                 NeoVimMessages::Unknown(ev) => {
-                    self.nvim
-                        .lock()
-                        .unwrap()
-                        .command(&format!("echoerr \"{}\" Unknown command", ev))
-                        .unwrap();
+                    if let Err(e) = self.unknown_command(&ev).await {
+                        error!("Unable to report unknown command {}: {}", ev, e);
+                    }
                 }
             }
         }
+
+        // The RPC channel closed, so Neovim is gone.
+        info!("Neovim RPC channel closed");
+        self.exit();
+    }
+
+    /// Answers an `rpcrequest` from a separate task, so notifications keep flowing meanwhile.
+    fn handle_request(&self, name: String, reply: oneshot::Sender<Result<Value, Value>>) {
+        match NeoVimMessages::from(name) {
+            NeoVimMessages::SerenadeStatus => {
+                let (status_reply, status) = oneshot::channel();
+                self.send(Control::Status {
+                    id: self.id.clone(),
+                    reply: status_reply,
+                });
+
+                tokio::spawn(async move {
+                    let answer = match status.await {
+                        Ok(v) => Ok(Value::from(v)),
+                        Err(_) => Err(Value::from("serenade: the Serenade task is gone")),
+                    };
+                    let _ = reply.send(answer);
+                });
+            }
+            NeoVimMessages::Unknown(name) => {
+                let _ = reply.send(Err(Value::from(format!("Unknown request {}", name))));
+            }
+            _ => {
+                let _ = reply.send(Err(Value::from("serenade: send this with rpcnotify")));
+            }
+        }
+    }
+
+    fn exit(&self) {
+        if self.daemon {
+            self.send(Control::Detach(self.id.clone()));
+        } else {
+            self.send(Control::Shutdown);
+        }
+    }
+
+    fn send(&self, control: Control) {
+        if self.tx.send(control).is_err() {
+            error!("Unable to send a control message to the Serenade task");
+        }
+    }
+
+    /// `:SerenadeRevert` passes its optional count as a string, defaulting to the latest snapshot.
+    fn snapshot_index(values: &[Value]) -> Result<usize, String> {
+        let arg = match values.first() {
+            Some(Value::String(v)) => v.as_str().unwrap_or("").trim().to_string(),
+            Some(v) => v.to_string(),
+            None => String::new(),
+        };
+
+        if arg.is_empty() {
+            return Ok(1);
+        }
+
+        match arg.parse() {
+            Ok(v) if v > 0 => return Ok(v),
+            _ => return Err(format!("invalid snapshot count \"{}\", expected 1 or more", arg)),
+        }
+    }
+
+    /// Logs a failed command and shows it in Neovim, like `Batch::failure` does.
+    async fn show_error(&self, message: &str) {
+        error!("{}", message);
+
+        if let Err(e) = self.nvim.err_writeln(&format!("serenade: {}", message)).await {
+            warn!("Unable to show error: {}", e);
+        }
+    }
+
+    async fn unknown_command(&self, event: &str) -> Result<(), BridgeError> {
+        self.nvim.command(&format!("echoerr \"{}\" Unknown command", event)).await?;
+
+        return Ok(());
+    }
+
+    async fn revert(&mut self, n: usize) -> Result<(), BridgeError> {
+        // Copied out, the history can't stay locked while waiting on Neovim.
+        let snapshot = match self.history.lock() {
+            Ok(history) => history.get(n).map(|v| (v.buffer.clone(), v.lines.clone(), v.cursor)),
+            _ => return Err(BridgeError::Lock("snapshot history")),
+        };
+
+        let (buffer, lines, cursor) = match snapshot {
+            Some(v) => v,
+            None => {
+                self.nvim.command(&format!("echoerr \"serenade: no snapshot {}\"", n)).await?;
+                return Ok(());
+            }
+        };
+
+        buffer.set_lines(0, -1, false, lines).await?;
+
+        let window = self.nvim.get_current_win().await?;
+        if window.get_buf().await? == buffer {
+            window.set_cursor(cursor).await?;
+        }
+
+        self.nvim.command(&format!("echom \"serenade: reverted to snapshot {}\"", n)).await?;
+
+        return Ok(());
+    }
+
+    async fn list_snapshots(&mut self) -> Result<(), BridgeError> {
+        let output = match self.history.lock() {
+            Ok(history) => {
+                let mut output = String::new();
+                for (i, snapshot) in history.newest_first().enumerate() {
+                    output.push_str(&format!("{:>3}  {}\n", i + 1, snapshot.summary()));
+                }
+                output
+            }
+            _ => return Err(BridgeError::Lock("snapshot history")),
+        };
+
+        if output.is_empty() {
+            self.nvim.out_write("serenade: no snapshots\n").await?;
+            return Ok(());
+        }
+        self.nvim.out_write(&output).await?;
+
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_index_defaults_to_the_latest() {
+        assert_eq!(NVimEventHandler::snapshot_index(&[]), Ok(1));
+        assert_eq!(NVimEventHandler::snapshot_index(&[Value::from(" ")]), Ok(1));
+        assert_eq!(NVimEventHandler::snapshot_index(&[Value::from(" 3 ")]), Ok(3));
+    }
+
+    #[test]
+    fn snapshot_index_rejects_anything_but_a_count() {
+        for arg in ["x", "-2", "0", "1.5"] {
+            assert!(NVimEventHandler::snapshot_index(&[Value::from(arg)]).is_err(), "{}", arg);
+        }
     }
 }
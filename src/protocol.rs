@@ -0,0 +1,346 @@
+use serde::de::{Deserializer, Error};
+use serde::{Deserialize, Serialize};
+
+/// Every message exchanged with the Serenade app, tagged by its `message` field.
+///
+/// Unknown message kinds and unknown fields are accepted and ignored, so newer versions of the
+/// app don't break parsing.
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "message", content = "data", rename_all = "camelCase")]
+pub enum SerenadeMessage {
+    /// Commands to run, sent by the app.
+    Response(SerenadeData),
+    /// Heartbeat announcing this editor to the app.
+    Active(HeartbeatData),
+    /// Result of a `Response`, sent back to the app.
+    Callback(SerenadeCallbackData),
+    Unknown,
+}
+
+#[derive(Deserialize)]
+struct Envelope {
+    message: String,
+    #[serde(default)]
+    data: serde_json::Value,
+}
+
+impl<'de> Deserialize<'de> for SerenadeMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let envelope = Envelope::deserialize(deserializer)?;
+        let message = match envelope.message.as_str() {
+            "response" => serde_json::from_value(envelope.data).map(SerenadeMessage::Response),
+            "active" => serde_json::from_value(envelope.data).map(SerenadeMessage::Active),
+            "callback" => serde_json::from_value(envelope.data).map(SerenadeMessage::Callback),
+            _ => return Ok(SerenadeMessage::Unknown),
+        };
+
+        return message.map_err(D::Error::custom);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(default)]
+pub struct HeartbeatData {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app: Option<String>,
+    #[serde(rename = "match", skip_serializing_if = "Option::is_none")]
+    pub match_term: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(default)]
+pub struct SerenadeData {
+    pub callback: String,
+    pub response: SerenadeResponse,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(default)]
+pub struct SerenadeResponse {
+    pub execute: SerenadeExecute,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SerenadeExecute {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub commands_list: Vec<SerenadeCommand>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub commands: Vec<SerenadeCommand>,
+}
+
+impl SerenadeExecute {
+    /// Older app versions send `commands`, newer ones `commandsList`.
+    pub fn commands(&self) -> &[SerenadeCommand] {
+        if self.commands_list.is_empty() {
+            return &self.commands;
+        }
+        return &self.commands_list;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SerenadeCommand {
+    #[serde(rename = "type")]
+    pub cmd_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor_end: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limited: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub direction: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub keys: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub modifiers: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub button: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SerenadeCallbackData {
+    pub callback: String,
+    pub data: CallbackResult,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "message", content = "data", rename_all = "camelCase")]
+pub enum CallbackResult {
+    Completed,
+    EditorState(SerenadeStateData),
+    /// The command was understood but could not be run.
+    Error(CallbackFailure),
+    /// The command type isn't available in Neovim.
+    Unsupported(CallbackFailure),
+}
+
+impl CallbackResult {
+    pub fn is_failure(&self) -> bool {
+        match self {
+            CallbackResult::Error(_) | CallbackResult::Unsupported(_) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(default)]
+pub struct CallbackFailure {
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct SerenadeStateData {
+    pub source: String,
+    pub cursor: u64,
+    pub selection_start: u64,
+    pub selection_end: u64,
+    pub filename: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(raw: &str) -> SerenadeMessage {
+        let parsed: SerenadeMessage = serde_json::from_str(raw).unwrap();
+        let serialized = serde_json::to_string(&parsed).unwrap();
+        let reparsed: SerenadeMessage = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(parsed, reparsed);
+        return parsed;
+    }
+
+    #[test]
+    fn parses_diff_response() {
+        let raw = r#"{"message":"response","data":{"callback":"4b2e","response":{"execute":{
+            "commandsList":[{"type":"COMMAND_TYPE_DIFF","source":"let x = 1;\n","cursor":8}],
+            "commands":[]}}}}"#;
+
+        match round_trip(raw) {
+            SerenadeMessage::Response(data) => {
+                assert_eq!(data.callback, "4b2e");
+                let commands = data.response.execute.commands();
+                assert_eq!(commands.len(), 1);
+                assert_eq!(commands[0].cmd_type, "COMMAND_TYPE_DIFF");
+                assert_eq!(commands[0].source.as_deref(), Some("let x = 1;\n"));
+                assert_eq!(commands[0].cursor, Some(8));
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    /// A whole `response` shaped like the app's: `validate` next to the callback, `commandsList`
+    /// alongside an empty `commands`, and every command field present with its default value.
+    const APP_RESPONSE: &str = r#"{
+        "message": "response",
+        "data": {
+            "callback": "0d7a5c1e-4f2b-4c8e-9b6a-3e1f2a7c9d40",
+            "validate": false,
+            "response": {
+                "execute": {
+                    "commandsList": [
+                        {
+                            "type": "COMMAND_TYPE_DIFF",
+                            "source": "def main():\n    return 1\n",
+                            "cursor": 15,
+                            "cursorEnd": 0,
+                            "filename": "",
+                            "path": "",
+                            "text": "",
+                            "limited": false,
+                            "index": 0,
+                            "count": 0,
+                            "direction": "",
+                            "keys": [],
+                            "modifiers": [],
+                            "app": "",
+                            "url": "",
+                            "button": ""
+                        },
+                        {
+                            "type": "COMMAND_TYPE_GET_EDITOR_STATE",
+                            "limited": true,
+                            "cursor": 0,
+                            "cursorEnd": 0,
+                            "source": ""
+                        }
+                    ],
+                    "commands": []
+                }
+            }
+        }
+    }"#;
+
+    #[test]
+    fn parses_full_app_response() {
+        match round_trip(APP_RESPONSE) {
+            SerenadeMessage::Response(data) => {
+                assert_eq!(data.callback, "0d7a5c1e-4f2b-4c8e-9b6a-3e1f2a7c9d40");
+                let commands = data.response.execute.commands();
+                assert_eq!(commands.len(), 2);
+                assert_eq!(commands[0].cmd_type, "COMMAND_TYPE_DIFF");
+                assert_eq!(commands[0].source.as_deref(), Some("def main():\n    return 1\n"));
+                assert_eq!(commands[0].cursor, Some(15));
+                assert_eq!(commands[0].cursor_end, Some(0));
+                assert_eq!(commands[1].cmd_type, "COMMAND_TYPE_GET_EDITOR_STATE");
+                assert_eq!(commands[1].limited, Some(true));
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn falls_back_to_commands_and_ignores_unknown_fields() {
+        let raw = r#"{"message":"response","data":{"callback":"9","validate":true,"response":{
+            "execute":{"commands":[{"type":"COMMAND_TYPE_PRESS","keys":["ctrl","s"],"count":2,
+            "somethingNew":{"nested":1}}]}}}}"#;
+
+        match round_trip(raw) {
+            SerenadeMessage::Response(data) => {
+                let commands = data.response.execute.commands();
+                assert_eq!(commands[0].keys, vec!["ctrl", "s"]);
+                assert_eq!(commands[0].count, Some(2));
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn accepts_response_without_execute() {
+        let raw = r#"{"message":"response","data":{"callback":"1","response":{}}}"#;
+
+        match round_trip(raw) {
+            SerenadeMessage::Response(data) => assert!(data.response.execute.commands().is_empty()),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ignores_unknown_message_kinds() {
+        let raw = r#"{"message":"somethingElse","data":{"anything":[1,2,3]}}"#;
+        let parsed: SerenadeMessage = serde_json::from_str(raw).unwrap();
+        assert_eq!(parsed, SerenadeMessage::Unknown);
+    }
+
+    #[test]
+    fn serializes_heartbeat() {
+        let heartbeat = SerenadeMessage::Active(HeartbeatData {
+            id: String::from("abc"),
+            app: Some(String::from("nvim")),
+            match_term: Some(String::from("kitty")),
+            icon: None,
+        });
+
+        assert_eq!(
+            serde_json::to_string(&heartbeat).unwrap(),
+            r#"{"message":"active","data":{"id":"abc","app":"nvim","match":"kitty"}}"#
+        );
+        round_trip(&serde_json::to_string(&heartbeat).unwrap());
+    }
+
+    #[test]
+    fn serializes_callbacks() {
+        let completed = SerenadeMessage::Callback(SerenadeCallbackData {
+            callback: String::from("7"),
+            data: CallbackResult::Completed,
+        });
+        assert_eq!(
+            serde_json::to_string(&completed).unwrap(),
+            r#"{"message":"callback","data":{"callback":"7","data":{"message":"completed"}}}"#
+        );
+
+        let state = SerenadeMessage::Callback(SerenadeCallbackData {
+            callback: String::from("8"),
+            data: CallbackResult::EditorState(SerenadeStateData {
+                source: String::from("fn main() {}"),
+                cursor: 3,
+                selection_start: 0,
+                selection_end: 2,
+                filename: String::from("main.rs"),
+            }),
+        });
+        let serialized = serde_json::to_string(&state).unwrap();
+        assert!(serialized.contains(r#""message":"editorState""#));
+        assert!(serialized.contains(r#""selectionStart":0"#));
+        assert_eq!(round_trip(&serialized), state);
+    }
+
+    #[test]
+    fn serializes_failure_callbacks() {
+        let unsupported = SerenadeMessage::Callback(SerenadeCallbackData {
+            callback: String::from("9"),
+            data: CallbackResult::Unsupported(CallbackFailure {
+                message: String::from("COMMAND_TYPE_CLICK is not supported in Neovim"),
+            }),
+        });
+        let serialized = serde_json::to_string(&unsupported).unwrap();
+        assert_eq!(
+            serialized,
+            r#"{"message":"callback","data":{"callback":"9","data":{"message":"unsupported","data":{"message":"COMMAND_TYPE_CLICK is not supported in Neovim"}}}}"#
+        );
+        assert_eq!(round_trip(&serialized), unsupported);
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::watch;
+
+/// Held by a request until it finishes. Dropping it, even by a panicking task, wakes its waiters.
+type Done = watch::Sender<()>;
+type Wait = watch::Receiver<()>;
+
+/// Nothing is ever sent, so this only returns once the matching `Done` is gone.
+async fn wait(mut wait: Wait) {
+    while wait.changed().await.is_ok() {}
+}
+
+fn is_done(wait: &Wait) -> bool {
+    return wait.has_changed().is_err();
+}
+
+/// Lets Serenade requests run alongside each other, while the ones for the same buffer keep the
+/// order they arrived in.
+///
+/// Requests are admitted one at a time, in order. A request for a single buffer is admitted once
+/// it knows its buffer, then waits for the previous request for that buffer. A request that may
+/// switch buffers waits for everything admitted before it and holds back everything after it.
+#[derive(Clone, Default)]
+pub struct Scheduler {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Admission of the request that arrived last.
+    last: Option<Wait>,
+    /// Last admitted request for each buffer, by buffer number.
+    buffers: HashMap<i64, Wait>,
+    /// Admitted buffer requests that may still be running.
+    running: Vec<Wait>,
+}
+
+/// A place in line, see `Scheduler::ticket`.
+pub struct Ticket {
+    scheduler: Scheduler,
+    previous: Option<Wait>,
+    admission: Done,
+}
+
+/// A request whose turn has come. Later requests aren't admitted until it says what it touches.
+pub struct Admitted {
+    scheduler: Scheduler,
+    admission: Done,
+}
+
+/// Keeps the requests that have to wait for this one waiting, until dropped.
+pub struct Guard {
+    _done: Done,
+}
+
+impl Scheduler {
+    /// Takes the next place in line. Has to be called in the order requests arrive.
+    pub fn ticket(&self) -> Ticket {
+        let (admission, admitted) = watch::channel(());
+        let previous = self.state().last.replace(admitted);
+
+        return Ticket {
+            scheduler: self.clone(),
+            previous,
+            admission,
+        };
+    }
+
+    /// Only held for bookkeeping that can't panic, but a poisoned lock still has valid state.
+    fn state(&self) -> MutexGuard<'_, State> {
+        match self.state.lock() {
+            Ok(v) => v,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl Ticket {
+    /// Waits until every earlier request was admitted.
+    pub async fn admit(self) -> Admitted {
+        if let Some(previous) = self.previous {
+            wait(previous).await;
+        }
+
+        return Admitted {
+            scheduler: self.scheduler,
+            admission: self.admission,
+        };
+    }
+}
+
+impl Admitted {
+    /// Waits for the earlier requests for buffer `number`, letting later requests be admitted
+    /// straight away.
+    pub async fn buffer(self, number: i64) -> Guard {
+        let (done, waiting) = watch::channel(());
+
+        let previous = {
+            let mut state = self.scheduler.state();
+            state.running.retain(|v| !is_done(v));
+            state.running.push(waiting.clone());
+            state.buffers.retain(|_, v| !is_done(v));
+            state.buffers.insert(number, waiting)
+        };
+        drop(self.admission);
+
+        if let Some(previous) = previous {
+            wait(previous).await;
+        }
+
+        return Guard { _done: done };
+    }
+
+    /// Waits for every earlier request, and keeps later ones from being admitted until the guard
+    /// is dropped.
+    pub async fn exclusive(self) -> Guard {
+        let running = {
+            let mut state = self.scheduler.state();
+            state.running.retain(|v| !is_done(v));
+            state.running.clone()
+        };
+
+        for v in running {
+            wait(v).await;
+        }
+
+        return Guard {
+            _done: self.admission,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+    use tokio::task::{self, JoinHandle};
+
+    /// Runs a request that records `name` once it is allowed to, then holds its guard until
+    /// `release` fires.
+    fn request(
+        scheduler: &Scheduler,
+        buffer: Option<i64>,
+        name: &'static str,
+        log: &Arc<Mutex<Vec<&'static str>>>,
+    ) -> (oneshot::Sender<()>, JoinHandle<()>) {
+        let ticket = scheduler.ticket();
+        let log = Arc::clone(log);
+        let (release, released) = oneshot::channel();
+
+        let handle = tokio::spawn(async move {
+            let admitted = ticket.admit().await;
+            let _guard = match buffer {
+                Some(v) => admitted.buffer(v).await,
+                None => admitted.exclusive().await,
+            };
+            log.lock().unwrap().push(name);
+            let _ = released.await;
+        });
+
+        return (release, handle);
+    }
+
+    async fn settle() {
+        for _ in 0..10 {
+            task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_order_within_a_buffer() {
+        let scheduler = Scheduler::default();
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        let (first, _) = request(&scheduler, Some(1), "first", &log);
+        let (second, second_done) = request(&scheduler, Some(1), "second", &log);
+        settle().await;
+        assert_eq!(*log.lock().unwrap(), vec!["first"]);
+
+        first.send(()).unwrap();
+        second.send(()).unwrap();
+        second_done.await.unwrap();
+        assert_eq!(*log.lock().unwrap(), vec!["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn runs_other_buffers_alongside() {
+        let scheduler = Scheduler::default();
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        let (_first, _) = request(&scheduler, Some(1), "first", &log);
+        let (second, second_done) = request(&scheduler, Some(2), "second", &log);
+        second.send(()).unwrap();
+        second_done.await.unwrap();
+
+        assert_eq!(*log.lock().unwrap(), vec!["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn exclusive_requests_wait_for_everything_before_and_after() {
+        let scheduler = Scheduler::default();
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        let (first, _) = request(&scheduler, Some(1), "first", &log);
+        let (switch, _) = request(&scheduler, None, "switch", &log);
+        let (third, third_done) = request(&scheduler, Some(2), "third", &log);
+        settle().await;
+        assert_eq!(*log.lock().unwrap(), vec!["first"]);
+
+        first.send(()).unwrap();
+        settle().await;
+        assert_eq!(*log.lock().unwrap(), vec!["first", "switch"]);
+
+        switch.send(()).unwrap();
+        third.send(()).unwrap();
+        third_done.await.unwrap();
+        assert_eq!(*log.lock().unwrap(), vec!["first", "switch", "third"]);
+    }
+}
@@ -1,18 +1,34 @@
+use crate::config::{Config, DiffLimitAction};
+use crate::connection::{self, Reader, Writer};
+use crate::diff::LineChange;
+use crate::error::BridgeError;
+use crate::indent::IndentStyle;
+use crate::instance::Instance;
+use crate::neovim::{self, Buffer, Window};
+use crate::protocol::{
+    CallbackFailure, CallbackResult, HeartbeatData, SerenadeCallbackData, SerenadeCommand,
+    SerenadeData, SerenadeMessage, SerenadeStateData,
+};
+use crate::schedule::Ticket;
+use crate::snapshot::Snapshot;
+use crate::transaction::Transaction;
+use crate::view;
+use futures::{future, SinkExt, StreamExt};
 use log::{debug, error, info, warn};
-use neovim_lib::{Neovim, NeovimApi, CallError, Value};
-use rand::Rng;
-use serde::{Deserialize, Serialize};
-use std::sync::mpsc::Receiver;
-use std::sync::{Arc, Mutex};
-use std::time::Instant;
-use std::net::TcpStream;
-use std::{thread, time::Duration};
-use tungstenite::WebSocket;
-use tungstenite::{connect, Message};
-use tungstenite::stream::MaybeTlsStream;
-use url::Url;
-
-const CONNECTION: &'static str = "ws://localhost:17373";
+use nvim_rs::Value;
+use std::cmp;
+use std::io;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::time::{self, Instant};
+use tokio_tungstenite::tungstenite::{Error, Message};
+
+/// How long a connection attempt may take before it counts as failed.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long shutting down waits for running batches to send their callbacks.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(PartialEq)]
 enum SerenadeMessages {
@@ -49,467 +65,969 @@ impl From<String> for SerenadeMessages {
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct HeartbeatData {
-    id: String,
-    app: Option<String>,
-    #[serde(rename = "match")]
-    match_term: Option<String>,
+impl SerenadeMessages {
+    /// Commands that change the current buffer, which every later command depends on.
+    fn switches_buffer(&self) -> bool {
+        return matches!(
+            self,
+            SerenadeMessages::NewTab
+                | SerenadeMessages::CloseTab
+                | SerenadeMessages::NextTab
+                | SerenadeMessages::PrevTab
+                | SerenadeMessages::SwitchTab
+        );
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-struct Heartbeat {
-    message: String,
-    data: HeartbeatData,
+/// Requests from the Neovim side to the Serenade task.
+pub enum Control {
+    /// `:SerenadeStart`, connecting first if the connection is lazy.
+    Start,
+    /// `:SerenadeStop`, pausing editing commands.
+    Stop,
+    /// Close the websocket and end the event loop.
+    Shutdown,
+    Focus(String),
+    Blur(String),
+    /// The instance with this id is gone, see the daemon.
+    Detach(String),
+    /// Drop the websocket and connect again straight away.
+    Reconnect,
+    /// Re-read the `g:serenade_*` globals of the instance with this id.
+    ReloadConfig(String),
+    /// Describe the instance with this id and the connection, answered through `reply`.
+    Status {
+        id: String,
+        reply: oneshot::Sender<String>,
+    },
 }
 
-#[derive(Deserialize, Debug)]
-struct SerenadeCommand {
-    #[serde(rename = "type")]
-    cmd_type: String,
-    source: Option<String>,
-    cursor: Option<u64>,
-    cursorEnd: Option<u64>,
-    limited: Option<bool>,
-    index: Option<u64>,
-    direction: Option<String>,
+/// Everything besides control messages the Serenade task waits on.
+pub enum Event {
+    /// A Neovim instance that connected to the daemon.
+    Attach(Instance),
+    /// A websocket read, tagged with the generation of the connection it came from.
+    Socket(u64, Result<Message, Error>),
+    /// The outcome of the connection attempt with this generation.
+    Connected(u64, Result<(Writer, Reader), Error>),
+    /// A finished batch, with the callback to send to Serenade if it has one.
+    Callback(Option<String>),
 }
 
-#[derive(Deserialize, Debug)]
-struct SerenadeExecute {
-    commandsList: Vec<SerenadeCommand>,
-    commands: Vec<SerenadeCommand>,
+/// Websocket connection state. A lost connection is retried with exponential backoff.
+enum ConnectionState {
+    /// Waiting for the first `:SerenadeStart` before connecting at all.
+    Idle,
+    /// A connection attempt runs in its own task, see `Event::Connected`.
+    Connecting { attempts: u32 },
+    Connected(Writer),
+    Disconnected { attempts: u32, retry_at: Instant },
 }
 
-#[derive(Deserialize, Debug)]
-struct SerenadeResponse {
-    execute: SerenadeExecute,
+pub struct SerenadeEventHandler {
+    is_paused: bool,
+    connection: ConnectionState,
+    has_connected: bool,
+    generation: u64,
+    controls: UnboundedReceiver<Control>,
+    events: UnboundedReceiver<Event>,
+    events_tx: UnboundedSender<Event>,
+    /// Instances commands can be sent to, and the index of the most recently focused one.
+    instances: Vec<Instance>,
+    active: usize,
+    exit_when_empty: bool,
+    exiting: bool,
+    /// Batches started by `handle_message` whose callback hasn't been handled yet.
+    running: usize,
+    /// Connection settings; per-buffer settings come from each instance's own config.
+    config: Config,
 }
 
-#[derive(Deserialize, Debug)]
-struct SerenadeData {
-    callback: String,
-    response: SerenadeResponse,
-}
+impl SerenadeEventHandler {
+    pub fn new(
+        config: Config,
+        instances: Vec<Instance>,
+        controls: UnboundedReceiver<Control>,
+    ) -> SerenadeEventHandler {
+        let connection = if config.lazy_connect {
+            ConnectionState::Idle
+        } else {
+            ConnectionState::Disconnected {
+                attempts: 0,
+                retry_at: Instant::now(),
+            }
+        };
 
-#[derive(Deserialize, Debug)]
-struct SerenadePayload {
-    message: String,
-    data: SerenadeData,
-}
+        let (events_tx, events) = unbounded_channel();
 
-#[derive(Serialize, Deserialize, Debug)]
-struct SerenadeStateData {
-    source: String,
-    cursor: u64,
-    selectionStart: u64,
-    selectionEnd: u64,
-    filename: String,
-}
+        return SerenadeEventHandler {
+            is_paused: false,
+            connection,
+            has_connected: false,
+            generation: 0,
+            controls,
+            events,
+            events_tx,
+            active: if instances.is_empty() { 0 } else { instances.len() - 1 },
+            instances,
+            exit_when_empty: false,
+            exiting: false,
+            running: 0,
+            config,
+        };
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct SerenadeEditorState {
-    message: String,
-    data: SerenadeStateData,
-}
+    /// Lets other tasks hand instances to the event loop.
+    pub fn event_sender(&self) -> UnboundedSender<Event> {
+        return self.events_tx.clone();
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct SerenadeCallbackMsg {
-    message: String,
-}
+    /// Shuts down once the last instance detaches, for daemons started on behalf of an editor.
+    pub fn set_exit_when_empty(&mut self, exit_when_empty: bool) {
+        self.exit_when_empty = exit_when_empty;
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct SerenadeCallbackData {
-    callback: String,
-    data: SerenadeCallbackMsg,
-}
+    /// Describes the instance and the connection state for `:SerenadeStatus`.
+    fn status(&self, id: &str) -> String {
+        let instance = match self.instances.iter().position(|i| i.id == id) {
+            Some(v) => v,
+            None => return format!("serenade: instance {} is not attached", id),
+        };
 
-#[derive(Serialize, Deserialize, Debug)]
-struct SerenadeStateCallbackData {
-    callback: String,
-    data: SerenadeEditorState,
-}
+        let connection = match self.connection {
+            ConnectionState::Idle => String::from("idle, waiting for :SerenadeStart"),
+            ConnectionState::Connecting { .. } => format!("connecting to {}", self.config.endpoint),
+            ConnectionState::Connected(_) => format!("connected to {}", self.config.endpoint),
+            ConnectionState::Disconnected { attempts, .. } => format!(
+                "disconnected from {}, {} reconnect attempts",
+                self.config.endpoint, attempts
+            ),
+        };
 
-#[derive(Serialize, Deserialize, Debug)]
-struct SerenadeStateCallback {
-    message: String,
-    data: SerenadeStateCallbackData,
-}
+        return format!(
+            "serenade: instance {}{}\nconnection: {}\ncommands: {}",
+            id,
+            if instance == self.active { " (active)" } else { "" },
+            connection,
+            if self.is_paused { "paused" } else { "listening" }
+        );
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct SerenadeCallback {
-    message: String,
-    data: SerenadeCallbackData,
-}
+    /// Picks up changed `g:serenade_*` globals without restarting the bridge.
+    async fn reload_config(&mut self, id: &str) {
+        let instance = match self.instances.iter_mut().find(|i| i.id == id) {
+            Some(v) => v,
+            None => return,
+        };
 
-pub struct SerenadeEventHandler {
-    id: u8,
-    is_paused: bool,
-    client: WebSocket<MaybeTlsStream<TcpStream>>,
-    rx: Receiver<String>,
-    nvim: Arc<Mutex<Neovim>>,
-    namespace: Option<i64>,
-    selected_positions: Option<Vec<(u64, u64)>>,
-}
+        let config = instance.config.reload(&instance.nvim).await;
 
-impl SerenadeEventHandler {
-    pub fn new(nvim: Arc<Mutex<Neovim>>, rx: Receiver<String>) -> SerenadeEventHandler {
-        let client = SerenadeEventHandler::create_client(CONNECTION);
+        match instance.history.lock() {
+            Ok(mut history) => history.set_capacity(config.snapshot_limit as usize),
+            _ => error!("Unable to lock snapshot history"),
+        }
+        // Connection settings are shared, the instance reloaded last decides them.
+        self.config.endpoint = config.endpoint.clone();
+        self.config.reconnect_max_delay = config.reconnect_max_delay;
+        self.config.heartbeat_interval = config.heartbeat_interval;
+        instance.config = config;
+        info!("Reloaded config of instance {}", id);
+    }
+
+    /// Drops the current connection, if any, and connects again without waiting for the backoff.
+    async fn force_reconnect(&mut self) {
+        if let ConnectionState::Connected(writer) = &mut self.connection {
+            if let Err(e) = writer.close().await {
+                warn!("Unable to close the Serenade connection cleanly: {:?}", e);
+            }
+        }
+
+        self.connection = ConnectionState::Disconnected {
+            attempts: 0,
+            retry_at: Instant::now(),
+        };
+    }
 
-        info!("Successfully connected");
+    fn paused_by_focus(&self) -> bool {
+        let instance = self.instance();
+        return instance.config.pause_unfocused && !instance.focused;
+    }
 
-        let mut rng = rand::thread_rng();
-        let random_id: u8 = rng.gen();
-        let mut namespace = None;
+    fn instance(&self) -> &Instance {
+        return &self.instances[self.active];
+    }
 
-        match nvim.lock() {
-            Ok(mut nvim) => {
-                namespace = Some(
-                    unwrap!(nvim.create_namespace("Serenade"))
-                );
-            }
-            _ => error!("Unable to lock nvim for \"redo\""),
+    async fn attach(&mut self, instance: Instance) {
+        info!("Attached instance {}", instance.id);
+
+        let heartbeat = SerenadeEventHandler::heartbeat_message(&instance, true);
+        self.instances.push(instance);
+        self.active = self.instances.len() - 1;
+
+        if let Some(v) = heartbeat {
+            self.send(Message::text(v)).await;
         }
+    }
 
-        return SerenadeEventHandler {
-            id: random_id,
-            is_paused: false,
-            client,
-            rx: rx,
-            nvim: nvim,
-            namespace,
-            selected_positions: None,
+    async fn detach(&mut self, id: &str) {
+        info!("Detached instance {}", id);
+
+        let active_id = self.instances.get(self.active).map(|i| i.id.clone());
+        self.instances.retain(|i| i.id != id);
+        self.active = match active_id.and_then(|a| self.instances.iter().position(|i| i.id == a)) {
+            Some(v) => v,
+            None => self.instances.len().saturating_sub(1),
         };
+
+        if self.instances.is_empty() && self.exit_when_empty {
+            self.shutdown().await;
+        }
     }
 
-    fn create_client(connection: &str) -> WebSocket<MaybeTlsStream<TcpStream>> {
-        info!("Connecting to: {}", CONNECTION);
+    /// Routes commands to the focused instance and tells Serenade about it straight away.
+    async fn focus(&mut self, id: &str) {
+        if let Some(index) = self.instances.iter().position(|i| i.id == id) {
+            debug!("Instance {} focused", id);
+            self.active = index;
+            self.instances[index].focused = true;
 
-        if let Ok((socket, _)) = connect(Url::parse(connection).unwrap()) {
-            return socket;
+            if let Some(v) = SerenadeEventHandler::heartbeat_message(&self.instances[index], true) {
+                self.send(Message::text(v)).await;
+            }
         }
+    }
 
-        thread::sleep(Duration::from_millis(1000));
+    fn blur(&mut self, id: &str) {
+        if let Some(instance) = self.instances.iter_mut().find(|i| i.id == id) {
+            debug!("Instance {} lost focus", id);
+            instance.focused = false;
+        }
+    }
 
-        return SerenadeEventHandler::create_client(connection);
+    async fn handle_control(&mut self, control: Control) {
+        match control {
+            Control::Start => {
+                self.is_paused = false;
+                if let ConnectionState::Idle = self.connection {
+                    self.connection = ConnectionState::Disconnected {
+                        attempts: 0,
+                        retry_at: Instant::now(),
+                    };
+                }
+            }
+            Control::Stop => self.is_paused = true,
+            Control::Focus(id) => self.focus(&id).await,
+            Control::Blur(id) => self.blur(&id),
+            Control::Detach(id) => self.detach(&id).await,
+            Control::Reconnect => self.force_reconnect().await,
+            Control::ReloadConfig(id) => self.reload_config(&id).await,
+            Control::Status { id, reply } => {
+                if reply.send(self.status(&id)).is_err() {
+                    warn!("Nobody is waiting for the status of {}", id);
+                }
+            }
+            Control::Shutdown => self.shutdown().await,
+        }
     }
 
-    pub fn heartbeat(&mut self, initial: bool) {
-        let heartbeat_data = Heartbeat {
-            message: "active".to_string(),
-            data: HeartbeatData {
-                id: self.id.to_string(),
-                app: if initial {
-                    Some(String::from("nvim"))
-                } else {
-                    None
-                },
-                match_term: if initial {
-                    Some(String::from("tilix"))
-                } else {
-                    None
-                },
-            },
+    fn is_connected(&self) -> bool {
+        return matches!(self.connection, ConnectionState::Connected(_));
+    }
+
+    /// Writes to the websocket, returning whether the message went out.
+    async fn send(&mut self, message: Message) -> bool {
+        let result = match &mut self.connection {
+            ConnectionState::Connected(writer) => writer.send(message).await,
+            _ => return false,
         };
 
-        match serde_json::to_string(&heartbeat_data) {
-            Ok(v) => {
-                self.client.write_message(Message::text(&v)).unwrap();
-                info!("Sent heartbeat {:?}", &v);
-            }
+        match result {
+            Ok(_) => true,
             Err(e) => {
-                warn!("Could not send heartbeat {:?}", e);
+                self.connection_lost(e).await;
+                false
             }
         }
     }
 
-    pub fn handle_events(&mut self) {
-        let start = Instant::now();
+    /// Sends the callback of a finished batch.
+    async fn finished(&mut self, callback: Option<String>) {
+        self.running = self.running.saturating_sub(1);
 
-        self.heartbeat(true);
+        if let Some(v) = callback {
+            debug!("writing to websocket: {:?}", v);
+            self.send(Message::text(v)).await;
+        }
+    }
 
-        loop {
-            // every minute
-            if start.elapsed().as_secs() % 60 == 0 {
-                self.heartbeat(false);
-            }
+    /// Waits for the running batches and sends their callbacks, so Serenade isn't left waiting.
+    /// Everything else that arrives meanwhile is dropped.
+    async fn drain(&mut self) {
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
 
-            match self.rx.try_recv() {
-                Ok(v) => match v.as_ref() {
-                    "start" => self.is_paused = false,
-                    "stop" => self.is_paused = true,
-                    _ => error!("Not a recognized cmd: {}", v),
-                },
+        while self.running > 0 {
+            match time::timeout_at(deadline, self.events.recv()).await {
+                Ok(Some(Event::Callback(v))) => self.finished(v).await,
+                Ok(Some(_)) => {}
                 _ => {
-                    debug!("No rx messages");
+                    warn!("Shutting down with {} batches still running", self.running);
+                    return;
                 }
-            };
+            }
+        }
+    }
 
-            let msg = match self.client.read_message() {
-                Ok(m) => m,
-                _ => continue,
-            };
+    /// Flushes pending callbacks and closes the websocket cleanly.
+    async fn shutdown(&mut self) {
+        info!("Shutting down");
+        self.drain().await;
 
-            debug!("again");
-            let raw_msg = match msg {
-                Message::Text(v) => v,
-                _ => continue,
-            };
+        if let ConnectionState::Connected(writer) = &mut self.connection {
+            if let Err(e) = writer.close().await {
+                warn!("Unable to close the Serenade connection cleanly: {:?}", e);
+            }
+        }
 
-            info!("received message from Serenade: {:?}", &raw_msg);
-            
-            let payload: SerenadePayload = match serde_json::from_str(&raw_msg) {
-                Ok(v) => v,
-                Err(e) => {
-                    error!("Unable to parse Serenade Message: {:?}", &raw_msg);
-                    continue;
-                },
-            };
+        self.connection = ConnectionState::Idle;
+        self.exiting = true;
+    }
 
-            let mut cb1 = None;
-            let mut cb2 = None;
-
-            for command in &payload.data.response.execute.commandsList {
-                let cmd = SerenadeMessages::from(command.cmd_type.to_string());
-                if cmd == SerenadeMessages::GetEditorState {
-                    cb1 = Some(SerenadeStateCallback {
-                        message: String::from("callback"),
-                        data: SerenadeStateCallbackData {
-                            callback: String::from(&payload.data.callback),
-                            data: self.get_editor_state(command.limited.unwrap_or_else(|| true)),
-                        },
-                    });
-                } else if !self.is_paused {
-                    let success = match cmd {
-                        SerenadeMessages::Diff => {
-                            self.diff(command.source.as_ref(), command.cursor.as_ref())
-                        }
-                        SerenadeMessages::Undo => self.undo(),
-                        SerenadeMessages::Redo => self.redo(),
-                        SerenadeMessages::Save => self.save(),
-                        SerenadeMessages::Select => self.select(
-                            command.cursor.unwrap_or_else(|| 0),
-                            command.cursorEnd.unwrap_or_else(|| 0),
-                        ),
-                        SerenadeMessages::NewTab => self.create_buffer(),
-                        SerenadeMessages::CloseTab => self.close_buffer(),
-                        SerenadeMessages::NextTab => self.next_buffer(),
-                        SerenadeMessages::PrevTab => self.prev_buffer(),
-                        SerenadeMessages::SwitchTab => self.switch_buffer(command.index.unwrap_or_else(|| 0)),
-                        _ => Ok(false)
-                    };
-                
-                    if let Ok(passed) = success {
-                        if passed == true {
-                            cb2 = Some(SerenadeCallback {
-                                message: String::from("callback"),
-                                data: SerenadeCallbackData {
-                                    callback: String::from(&payload.data.callback),
-                                    data: SerenadeCallbackMsg {
-                                        message: String::from("completed"),
-                                    },
-                                },
-                            });
-                        }
-                    } 
-                }
+    async fn connection_lost(&mut self, e: Error) {
+        warn!("Lost connection to Serenade: {:?}", e);
 
-                info!("{}", command.cmd_type);
-            }
+        self.connection = ConnectionState::Disconnected {
+            attempts: 0,
+            retry_at: Instant::now(),
+        };
+        self.notify("serenade: connection lost, reconnecting").await;
+    }
 
-            let mut cb_serialized = None;
+    /// Starts a connection attempt in its own task, so control messages are handled meanwhile.
+    fn reconnect(&mut self) {
+        let attempts = match self.connection {
+            ConnectionState::Disconnected { attempts, .. } => attempts,
+            _ => return,
+        };
 
-            if cb1.is_some() {
-                cb_serialized = Some(serde_json::to_string(&cb1.unwrap()).unwrap());
-            } else if cb2.is_some() {
-                cb_serialized = Some(serde_json::to_string(&cb2.unwrap()).unwrap());
-            } else {
-                continue;
-            }
+        self.generation += 1;
+        self.connection = ConnectionState::Connecting { attempts };
 
-            debug!("writing to websocket: {:?}", cb_serialized);
+        let generation = self.generation;
+        let endpoint = self.config.endpoint.clone();
+        let events_tx = self.events_tx.clone();
 
-            self.client
-                .write_message(Message::text(cb_serialized.unwrap()))
-                .unwrap();
+        tokio::spawn(async move {
+            let result = match time::timeout(CONNECT_TIMEOUT, connection::connect(&endpoint)).await {
+                Ok(v) => v,
+                Err(_) => Err(Error::Io(io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))),
+            };
 
-            thread::sleep(Duration::from_millis(50));
-        }
+            let _ = events_tx.send(Event::Connected(generation, result));
+        });
     }
 
-    fn get_editor_state(&mut self, limited: bool) -> SerenadeEditorState {
-        let mut result = SerenadeEditorState {
-            message: String::from("editorState"),
-            data: SerenadeStateData {
-                source: String::from(""),
-                cursor: 0,
-                selectionStart: 0,
-                selectionEnd: 0,
-                filename: String::from(""),
-            },
+    /// Takes over the connection from `reconnect`, returning whether it worked.
+    async fn connected(&mut self, generation: u64, result: Result<(Writer, Reader), Error>) -> bool {
+        let attempts = match self.connection {
+            ConnectionState::Connecting { attempts } if generation == self.generation => attempts,
+            // Stopped, shut down or restarted meanwhile.
+            _ => return false,
         };
 
-        match self.nvim.lock() {
-            Ok(mut nvim) => {
-                let buffer = match nvim.get_current_buf() {
-                    Ok(v) => v,
-                    _ => {
-                        error!("Could not get current buffer");
-                        return result;
-                    }
-                };
-                let full_file_name = buffer.get_name(&mut nvim).unwrap();
-                let file_name_pieces: Vec<&str> = full_file_name.split('/').collect();
-                let file_name = file_name_pieces[file_name_pieces.len() - 1];
-
-                result.data.filename = String::from(file_name);
-                if limited != true {
-                    let window = nvim.get_current_win().unwrap();
-                    let lines = buffer.get_lines(&mut nvim, 0, -1, false).unwrap();
-                    let cursor = window.get_cursor(&mut nvim).unwrap();
-                    let mark_start = buffer.get_mark(&mut nvim, "<").unwrap();
-                    let mark_end = buffer.get_mark(&mut nvim, ">").unwrap();
-                    result.data.source = lines.join("\n");
-                    result.data.cursor =
-                        SerenadeEventHandler::get_cursor_position(&result.data.source, cursor);
-                    result.data.selectionStart =
-                        SerenadeEventHandler::get_cursor_position(&result.data.source, mark_start);
-                    result.data.selectionEnd =
-                        SerenadeEventHandler::get_cursor_position(&result.data.source, mark_end);
+        match result {
+            Ok((writer, reader)) => {
+                info!("Connected to {}", self.config.endpoint);
+                self.spawn_reader(reader);
+                self.connection = ConnectionState::Connected(writer);
+                self.heartbeat(true).await;
+
+                if self.has_connected {
+                    self.notify("serenade: connection restored").await;
                 }
+                self.has_connected = true;
+
+                return true;
+            }
+            Err(e) => {
+                let delay = cmp::min(1u64 << cmp::min(attempts, 16), self.config.reconnect_max_delay);
+                debug!("Reconnect attempt {} failed, retrying in {}s: {:?}", attempts + 1, delay, e);
+                self.connection = ConnectionState::Disconnected {
+                    attempts: attempts + 1,
+                    retry_at: Instant::now() + Duration::from_secs(cmp::max(delay, 1)),
+                };
+
+                return false;
             }
-            _ => error!("Unable to lock nvim for \"get editor state\""),
         }
+    }
 
-        return result;
+    /// Reads from the websocket in a separate task until the connection fails.
+    fn spawn_reader(&self, mut reader: Reader) {
+        let generation = self.generation;
+        let events_tx = self.events_tx.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let result = match reader.next().await {
+                    Some(v) => v,
+                    None => Err(Error::ConnectionClosed),
+                };
+                let failed = result.is_err();
+
+                if events_tx.send(Event::Socket(generation, result)).is_err() || failed {
+                    break;
+                }
+            }
+        });
     }
 
-    fn undo(&mut self) -> Result<bool, CallError> {
-        match self.nvim.lock() {
-            Ok(mut nvim) => {
-                nvim.command(":undo")?;
-                return Ok(true);
-            },
-            _ => {
-                error!("Unable to lock nvim for \"undo\"");
-                return Ok(false);
-            },
+    async fn notify(&self, message: &str) {
+        for instance in &self.instances {
+            if let Err(e) = instance.nvim.command(&format!("echom \"{}\"", message)).await {
+                warn!("Unable to notify nvim: {}", e);
+            }
         }
     }
 
-    fn redo(&mut self) -> Result<bool, CallError> {
-        match self.nvim.lock() {
-            Ok(mut nvim) => {
-                nvim.command(":redo")?;
-                return Ok(true);
-            },
-            _ => {
-                error!("Unable to lock nvim for \"redo\"");
-                return Ok(false);
-            },
+    /// Sends a heartbeat for every attached instance, so Serenade sees all of them as apps.
+    pub async fn heartbeat(&mut self, initial: bool) {
+        let messages: Vec<String> = self
+            .instances
+            .iter()
+            .filter_map(|instance| SerenadeEventHandler::heartbeat_message(instance, initial))
+            .collect();
+
+        for v in messages {
+            if self.send(Message::text(&v)).await {
+                info!("Sent heartbeat {:?}", &v);
+            }
         }
     }
 
-    fn save(&mut self) -> Result<bool, CallError> {
-        match self.nvim.lock() {
-            Ok(mut nvim) => {
-                nvim.command(":w")?;
-                return Ok(true);
+    fn heartbeat_message(instance: &Instance, initial: bool) -> Option<String> {
+        let heartbeat = SerenadeMessage::Active(HeartbeatData {
+            id: instance.id.clone(),
+            app: if initial {
+                Some(instance.config.app_name.clone())
+            } else {
+                None
             },
-            _ => {
-                error!("Unable to lock nvim for \"save\"");
-                return Ok(false);
+            match_term: if initial {
+                Some(instance.config.match_term.clone())
+            } else {
+                None
             },
+            icon: if initial {
+                instance.config.icon.clone()
+            } else {
+                None
+            },
+        });
+
+        match serde_json::to_string(&heartbeat) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                warn!("Could not send heartbeat {:?}", e);
+                None
+            }
         }
     }
 
-    fn select(&mut self, start: u64, end: u64) -> Result<bool, CallError> {
-        match self.nvim.lock() {
-            Ok(mut nvim) => { 
-                let buffer = nvim.get_current_buf()?;
-                let lines = buffer.get_lines(&mut nvim, 0, -1, false)?;
-                let source = lines.join("\n");
-                let start_mark = SerenadeEventHandler::get_cursor_position_rev(&source, &start);
-                let end_mark = SerenadeEventHandler::get_cursor_position_rev(&source, &end);
-                
-                nvim.command(&format!(":cal cursor({}, {})", start_mark.0, start_mark.1))?;
-                
-                if let Some(namespace) = self.namespace {
-                    buffer.set_extmark(&mut nvim, namespace, (start_mark.0 -  1) as i64, (start_mark.1 - 1) as i64, vec![(Value::from("end_line"), Value::from(end_mark.0 - 1)), (Value::from("end_col"), Value::from(end_mark.1 - 1)), (Value::from("hl_group"), Value::from("Visual"))])?;
+    fn heartbeat_interval(&self) -> Duration {
+        return Duration::from_secs(cmp::max(self.config.heartbeat_interval, 1));
+    }
+
+    pub async fn handle_events(&mut self) {
+        let mut next_heartbeat = Instant::now();
+
+        loop {
+            let deadline = match self.connection {
+                ConnectionState::Idle | ConnectionState::Connecting { .. } => None,
+                ConnectionState::Connected(_) => Some(next_heartbeat),
+                ConnectionState::Disconnected { retry_at, .. } => Some(retry_at),
+            };
+            let timer = async move {
+                match deadline {
+                    Some(v) => time::sleep_until(v).await,
+                    None => future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                control = self.controls.recv() => match control {
+                    Some(v) => self.handle_control(v).await,
+                    None => return,
+                },
+                Some(event) = self.events.recv() => match event {
+                    Event::Attach(instance) => self.attach(instance).await,
+                    Event::Socket(generation, result) => {
+                        if generation != self.generation || !self.is_connected() {
+                            continue;
+                        }
+                        match result {
+                            Ok(msg) => self.handle_message(msg),
+                            Err(e) => self.connection_lost(e).await,
+                        }
+                    }
+                    Event::Connected(generation, result) => {
+                        if self.connected(generation, result).await {
+                            next_heartbeat = Instant::now() + self.heartbeat_interval();
+                        }
+                    }
+                    Event::Callback(v) => self.finished(v).await,
+                },
+                _ = timer => {
+                    if self.is_connected() {
+                        self.heartbeat(false).await;
+                        next_heartbeat = Instant::now() + self.heartbeat_interval();
+                    } else {
+                        self.reconnect();
+                    }
                 }
-                
-                return Ok(true);
-            },
-            _ => {
-                error!("Unable to lock nvim for \"select\"");
-                return Ok(false)
+            }
+
+            if self.exiting {
+                return;
             }
         }
     }
 
-    fn switch_buffer(&mut self, index: u64) -> Result<bool, CallError> {
-        match self.nvim.lock() {
-            Ok(mut nvim) => {
-                nvim.command(&format!(":b {}", index))?;
-                return Ok(true);
+    /// Starts a batch of commands in its own task, so the next request doesn't have to wait for
+    /// it unless it touches the same buffer, see `Scheduler`.
+    fn handle_message(&mut self, msg: Message) {
+        let raw_msg = match msg {
+            Message::Text(v) => v,
+            _ => return,
+        };
+
+        info!("received message from Serenade: {:?}", &raw_msg);
+
+        if self.instances.is_empty() {
+            warn!("No Neovim instance to run the command in");
+            return;
+        }
+
+        let payload = match serde_json::from_str(&raw_msg) {
+            Ok(SerenadeMessage::Response(v)) => v,
+            Ok(other) => {
+                debug!("Ignoring Serenade message: {:?}", other);
+                return;
+            }
+            Err(e) => {
+                error!("Unable to parse Serenade Message {:?}: {:?}", &raw_msg, e);
+                return;
+            }
+        };
+
+        let batch = Batch {
+            instance: self.instance().clone(),
+            paused: if self.is_paused {
+                Some("commands are paused, see :SerenadeStart")
+            } else if self.paused_by_focus() {
+                Some("Neovim doesn't have focus")
+            } else {
+                None
             },
-            _ => {
-                error!("Unable to lock nvim for \"switch tab\"");
-                return Ok(false);
+            target: None,
+        };
+        let ticket = batch.instance.scheduler.ticket();
+        let nvim = batch.instance.nvim.clone();
+        let callback = payload.callback.clone();
+        let events_tx = self.events_tx.clone();
+        let task = tokio::spawn(batch.run(ticket, payload));
+        self.running += 1;
+
+        // A panicking batch still owes Serenade an answer. Its scheduler guard is dropped while
+        // unwinding, so the requests waiting for it go on.
+        tokio::spawn(async move {
+            let result = match task.await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("A batch of Serenade commands crashed: {}", e);
+
+                    let message = "serenade: a voice command crashed, see the log";
+                    if let Err(e) = nvim.err_writeln(message).await {
+                        warn!("Unable to notify nvim: {}", e);
+                    }
+
+                    Batch::callback(
+                        &callback,
+                        CallbackResult::Error(CallbackFailure {
+                            message: String::from("the command crashed in Neovim"),
+                        }),
+                    )
+                }
+            };
+
+            if events_tx.send(Event::Callback(result)).is_err() {
+                warn!("Dropping a callback, the Serenade task is gone");
+            }
+        });
+    }
+}
+
+/// The commands of one Serenade request, run against the instance that was active when it arrived.
+struct Batch {
+    instance: Instance,
+    /// Why editing commands are skipped, see `:SerenadeStop` and `g:serenade_pause_unfocused`.
+    paused: Option<&'static str>,
+    /// The buffer the commands work on, or `None` while they may switch buffers and work on
+    /// whichever one is current.
+    target: Option<Buffer>,
+}
+
+impl Batch {
+    /// Runs the commands once the scheduler lets them, returning the callback for Serenade.
+    async fn run(mut self, ticket: Ticket, payload: SerenadeData) -> Option<String> {
+        let commands = payload.response.execute.commands();
+        let switches_buffer = commands
+            .iter()
+            .any(|v| SerenadeMessages::from(v.cmd_type.to_string()).switches_buffer());
+
+        let admitted = ticket.admit().await;
+        let _guard = if switches_buffer {
+            admitted.exclusive().await
+        } else {
+            let target = match self.current_buffer().await {
+                Ok(v) => v,
+                Err(e) => {
+                    let result = self.failure("Finding the current buffer", e).await;
+                    return Batch::callback(&payload.callback, result);
+                }
+            };
+            let guard = admitted.buffer(target.1).await;
+            self.target = Some(target.0);
+            guard
+        };
+
+        let mut results = Vec::new();
+        let mut transaction = if self.instance.config.transactional_batches {
+            Some(Transaction::new())
+        } else {
+            None
+        };
+
+        for command in commands {
+            info!("{}", command.cmd_type);
+
+            let captured = match transaction.as_mut() {
+                Some(transaction) => self.capture(transaction).await,
+                None => Ok(()),
+            };
+
+            let result = match captured {
+                Ok(()) => self.run_command(command).await,
+                Err(e) => self.failure(&command.cmd_type, e).await,
+            };
+            let failed = result.is_failure();
+            results.push(result);
+
+            // Later commands in a batch build on the earlier ones, so there's no point going on.
+            if failed {
+                if let Some(transaction) = transaction.take() {
+                    self.rollback(transaction).await;
+                }
+                break;
             }
         }
+
+        return Batch::callback(&payload.callback, Batch::aggregate(results));
     }
 
-    fn close_buffer(&mut self) -> Result<bool, CallError> {
-        match self.nvim.lock() {
-            Ok(mut nvim) => {
-                nvim.command(":bd")?;
-                return Ok(true);
+    /// Runs a single command. Editing commands fail while commands are paused, so Serenade can
+    /// tell the user why nothing happened.
+    async fn run_command(&self, command: &SerenadeCommand) -> CallbackResult {
+        let cmd = SerenadeMessages::from(command.cmd_type.to_string());
+        if cmd == SerenadeMessages::GetEditorState {
+            return match self.get_editor_state(command.limited.unwrap_or(true)).await {
+                Ok(v) => CallbackResult::EditorState(v),
+                Err(e) => self.failure(&command.cmd_type, e).await,
+            };
+        }
+
+        if let Some(reason) = self.paused {
+            info!("Skipping {}, {}", command.cmd_type, reason);
+            return CallbackResult::Error(CallbackFailure {
+                message: format!("{} was skipped, {}", command.cmd_type, reason),
+            });
+        }
+
+        let success = match cmd {
+            SerenadeMessages::Diff => {
+                self.diff(command.source.as_ref(), command.cursor.as_ref()).await
             }
-            _ => {
-                error!("Unable to lock nvim for \"close tab\"");
-                return Ok(false);
+            SerenadeMessages::Undo => self.undo().await,
+            SerenadeMessages::Redo => self.redo().await,
+            SerenadeMessages::Save => self.save().await,
+            SerenadeMessages::Select => {
+                self.select(command.cursor.unwrap_or(0), command.cursor_end.unwrap_or(0)).await
             }
+            SerenadeMessages::NewTab => self.create_buffer().await,
+            SerenadeMessages::CloseTab => self.close_buffer().await,
+            SerenadeMessages::NextTab => self.next_buffer().await,
+            SerenadeMessages::PrevTab => self.prev_buffer().await,
+            SerenadeMessages::SwitchTab => self.switch_buffer(command.index.unwrap_or(0)).await,
+            SerenadeMessages::Unknown(ref v) => {
+                warn!("Unsupported Serenade command {}", v);
+                return CallbackResult::Unsupported(CallbackFailure {
+                    message: format!("{} is not supported in Neovim", v),
+                });
+            }
+            SerenadeMessages::GetEditorState => Ok(false),
+        };
+
+        match success {
+            Ok(true) => return CallbackResult::Completed,
+            Ok(false) => {
+                return CallbackResult::Error(CallbackFailure {
+                    message: format!("{} was not applied", command.cmd_type),
+                })
+            }
+            Err(e) => return self.failure(&command.cmd_type, e).await,
+        }
+    }
+
+    /// Logs a failed command, shows it in Neovim if the user can do something about it, and
+    /// turns it into the result reported to Serenade.
+    async fn failure(&self, cmd_type: &str, e: BridgeError) -> CallbackResult {
+        error!("{} failed: {}", cmd_type, e);
+
+        if e.is_user_relevant() {
+            self.show_error(&e).await;
         }
+
+        return CallbackResult::Error(CallbackFailure {
+            message: e.to_string(),
+        });
     }
 
-    fn create_buffer(&mut self) -> Result<bool, CallError> {
-        match self.nvim.lock() {
-            Ok(mut nvim) => {
-                nvim.command(":enew")?;
-                return Ok(true);
+    async fn show_error(&self, e: &BridgeError) {
+        if let Err(e) = self.instance.nvim.err_writeln(&format!("serenade: {}", e)).await {
+            warn!("Unable to show error: {}", e);
+        }
+    }
+
+    async fn capture(&self, transaction: &mut Transaction) -> Result<(), BridgeError> {
+        let buffer = self.buffer().await?;
+        transaction.capture(&self.instance.nvim, &buffer).await?;
+        return Ok(());
+    }
+
+    async fn rollback(&self, transaction: Transaction) {
+        if let Err(e) = transaction.rollback(&self.instance.nvim).await {
+            error!("Could not roll back failed batch: {}", e);
+        }
+    }
+
+    /// Folds the results of a batch into the one callback Serenade waits for: the first failure
+    /// if there was one, otherwise the last editor state, otherwise plain completion. An empty
+    /// batch completes too, Serenade waits for its callback all the same.
+    fn aggregate(results: Vec<CallbackResult>) -> CallbackResult {
+        let mut aggregated = CallbackResult::Completed;
+
+        for result in results {
+            if result.is_failure() {
+                return result;
             }
-            _ => {
-                error!("Unable to lock nvim for \"create tab\"");
-                return Ok(false);
+
+            match (&aggregated, &result) {
+                (CallbackResult::EditorState(_), CallbackResult::Completed) => {}
+                _ => aggregated = result,
             }
         }
+
+        return aggregated;
     }
 
-    fn next_buffer(&mut self) -> Result<bool, CallError> {
-        match self.nvim.lock() {
-            Ok(mut nvim) => {
-                nvim.command(":bnext")?;
-                return Ok(true);
-            },
-            _ => {
-                error!("Unable to lock nvim for \"next tab\"");
-                return Ok(false);
+    fn callback(callback: &str, result: CallbackResult) -> Option<String> {
+        let message = SerenadeMessage::Callback(SerenadeCallbackData {
+            callback: String::from(callback),
+            data: result,
+        });
+
+        match serde_json::to_string(&message) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                error!("Could not serialize callback {:?}", e);
+                None
             }
         }
     }
 
-    fn prev_buffer(&mut self) -> Result<bool, CallError> {
-        match self.nvim.lock() {
-            Ok(mut nvim) => {
-                nvim.command(":bprevious")?;
-                return Ok(true);
-            }
-            _ => {
-                error!("Unable to lock nvim for \"previous tab\"");
-                return Ok(false);
+    async fn current_buffer(&self) -> Result<(Buffer, i64), BridgeError> {
+        let buffer = self.instance.nvim.get_current_buf().await?;
+        let number = buffer.get_number().await?;
+
+        return Ok((buffer, number));
+    }
+
+    /// The buffer the next command works on, see `target`.
+    async fn buffer(&self) -> Result<Buffer, BridgeError> {
+        match &self.target {
+            Some(v) => return Ok(v.clone()),
+            None => return Ok(self.instance.nvim.get_current_buf().await?),
+        }
+    }
+
+    /// The current window, as long as it still shows `buffer`. Cursor moves only go there.
+    async fn window(&self, buffer: &Buffer) -> Result<Option<Window>, BridgeError> {
+        let window = self.instance.nvim.get_current_win().await?;
+
+        if window.get_buf().await? != *buffer {
+            return Ok(None);
+        }
+
+        return Ok(Some(window));
+    }
+
+    /// Reads the buffer in a single round trip, see `neovim::atomic`.
+    async fn get_editor_state(&self, limited: bool) -> Result<SerenadeStateData, BridgeError> {
+        let mut result = SerenadeStateData::default();
+        let buffer = self.buffer().await?;
+        let target = || buffer.get_value().clone();
+        let current = || Value::from(0);
+
+        let mut calls = vec![("nvim_buf_get_name", vec![target()])];
+        if !limited {
+            calls.push((
+                "nvim_buf_get_lines",
+                vec![target(), Value::from(0), Value::from(-1), Value::from(false)],
+            ));
+            calls.push(("nvim_win_get_buf", vec![current()]));
+            calls.push(("nvim_win_get_cursor", vec![current()]));
+            calls.push(("nvim_buf_get_mark", vec![target(), Value::from("<")]));
+            calls.push(("nvim_buf_get_mark", vec![target(), Value::from(">")]));
+        }
+
+        let values = neovim::atomic(&self.instance.nvim, calls).await?;
+
+        let full_file_name = values.first().and_then(|v| v.as_str()).unwrap_or("");
+        let file_name = full_file_name.rsplit('/').next().unwrap_or("");
+
+        result.filename = String::from(file_name);
+        if !limited {
+            let lines: Vec<&str> = match values.get(1).and_then(|v| v.as_array()) {
+                Some(v) => v.iter().filter_map(|l| l.as_str()).collect(),
+                None => return Err(BridgeError::Protocol(String::from("no buffer lines"))),
+            };
+            // The cursor only belongs to the buffer while the current window shows it.
+            let cursor = if values.get(2) == Some(&target()) {
+                position(values.get(3))
+            } else {
+                (1, 0)
+            };
+
+            result.source = lines.join("\n");
+            result.cursor = Batch::get_cursor_position(&result.source, cursor);
+            result.selection_start =
+                Batch::get_cursor_position(&result.source, position(values.get(4)));
+            result.selection_end =
+                Batch::get_cursor_position(&result.source, position(values.get(5)));
+        }
+
+        return Ok(result);
+    }
+
+    async fn undo(&self) -> Result<bool, BridgeError> {
+        self.snapshot_command("undo").await?;
+        return Ok(true);
+    }
+
+    async fn redo(&self) -> Result<bool, BridgeError> {
+        self.snapshot_command("redo").await?;
+        return Ok(true);
+    }
+
+    async fn save(&self) -> Result<bool, BridgeError> {
+        let buffer = self.buffer().await?;
+        self.in_buffer(&buffer, "w").await?;
+        return Ok(true);
+    }
+
+    async fn select(&self, start: u64, end: u64) -> Result<bool, BridgeError> {
+        let nvim = &self.instance.nvim;
+        let buffer = self.buffer().await?;
+        let lines = buffer.get_lines(0, -1, false).await?;
+        let source = lines.join("\n");
+        let start_mark = Batch::get_cursor_position_rev(&source, &start);
+        let end_mark = Batch::get_cursor_position_rev(&source, &end);
+        // Extmarks count from 0, and offset 0 comes back as column 0.
+        let extmark = |(line, column): (u64, u64)| {
+            ((line as i64).saturating_sub(1).max(0), (column as i64).saturating_sub(1).max(0))
+        };
+        let (start_line, start_col) = extmark(start_mark);
+        let (end_line, end_col) = extmark(end_mark);
+
+        if let Some(window) = self.window(&buffer).await? {
+            let (line, _) = window.get_cursor().await?;
+
+            if self.is_long_jump(line, start_mark.0 as i64) {
+                nvim.command("normal! m'").await?;
             }
+
+            nvim.command(&format!(":cal cursor({}, {})", start_mark.0, start_mark.1)).await?;
+        }
+
+        if let Some(namespace) = self.instance.namespace {
+            let options = vec![
+                (Value::from("end_line"), Value::from(end_line)),
+                (Value::from("end_col"), Value::from(end_col)),
+                (Value::from("hl_group"), Value::from("Visual")),
+            ];
+            buffer.set_extmark(namespace, start_line, start_col, options).await?;
         }
+
+        return Ok(true);
+    }
+
+    async fn switch_buffer(&self, index: u64) -> Result<bool, BridgeError> {
+        self.commands(&["normal! m'", &format!(":b {}", index)]).await?;
+        return Ok(true);
+    }
+
+    async fn close_buffer(&self) -> Result<bool, BridgeError> {
+        self.instance.nvim.command(":bd").await?;
+        return Ok(true);
+    }
+
+    async fn create_buffer(&self) -> Result<bool, BridgeError> {
+        self.instance.nvim.command(":enew").await?;
+        return Ok(true);
+    }
+
+    async fn next_buffer(&self) -> Result<bool, BridgeError> {
+        self.commands(&["normal! m'", ":bnext"]).await?;
+        return Ok(true);
+    }
+
+    async fn prev_buffer(&self) -> Result<bool, BridgeError> {
+        self.commands(&["normal! m'", ":bprevious"]).await?;
+        return Ok(true);
+    }
+
+    /// Runs ex commands in order, in one round trip.
+    async fn commands(&self, commands: &[&str]) -> Result<(), BridgeError> {
+        let calls = commands
+            .iter()
+            .map(|v| ("nvim_command", vec![Value::from(*v)]))
+            .collect();
+
+        neovim::atomic(&self.instance.nvim, calls).await?;
+        return Ok(());
+    }
+
+    /// Runs an ex command as if `buffer` were current, without touching any window.
+    async fn in_buffer(&self, buffer: &Buffer, command: &str) -> Result<(), BridgeError> {
+        self.instance
+            .nvim
+            .exec_lua(
+                "local buffer, command = ...\n\
+                 vim.api.nvim_buf_call(buffer, function() vim.cmd(command) end)",
+                vec![buffer.get_value().clone(), Value::from(command)],
+            )
+            .await?;
+
+        return Ok(());
     }
 
     fn get_cursor_position(source: &str, cursor: (i64, i64)) -> u64 {
@@ -550,26 +1068,210 @@ impl SerenadeEventHandler {
         return (line_num, column);
     }
 
-    fn diff(&mut self, source: Option<&String>, cursor: Option<&u64>) -> Result<bool, CallError> {
-        match self.nvim.lock() {
-            Ok(mut nvim) => {
-                let buffer = nvim.get_current_buf()?;
-                let window = nvim.get_current_win()?;
-                let cursor_pos = SerenadeEventHandler::get_cursor_position_rev(
-                    &source.unwrap(),
-                    cursor.unwrap(),
-                );
-                let lines: Vec<String> = source.unwrap().lines().map(|s| s.to_string()).collect();
-
-                buffer.set_lines(&mut nvim, 0, -1, false, lines)?;
-                window.set_cursor(&mut nvim, (cursor_pos.0 as i64, cursor_pos.1 as i64))?;
-                    
-                return Ok(true);
-            }
-            _ => {
-                error!("Unable to lock nvim for \"diff\"");
+    async fn record_snapshot(
+        &self,
+        buffer: &Buffer,
+        lines: Vec<String>,
+        cursor: (i64, i64),
+        change: LineChange,
+    ) -> Result<(), BridgeError> {
+        if change.is_empty() {
+            return Ok(());
+        }
+
+        let name = buffer.get_name().await?;
+
+        match self.instance.history.lock() {
+            Ok(mut history) => history.push(Snapshot {
+                buffer: buffer.clone(),
+                name,
+                taken_at: SystemTime::now(),
+                lines,
+                cursor,
+                change,
+            }),
+            _ => error!("Unable to lock snapshot history"),
+        }
+
+        return Ok(());
+    }
+
+    /// Runs an ex command that edits the buffer, snapshotting it first.
+    async fn snapshot_command(&self, command: &str) -> Result<(), BridgeError> {
+        let buffer = self.buffer().await?;
+        let before = buffer.get_lines(0, -1, false).await?;
+        let cursor = match self.window(&buffer).await? {
+            Some(window) => window.get_cursor().await?,
+            None => (1, 0),
+        };
+
+        self.in_buffer(&buffer, command).await?;
+
+        let after = buffer.get_lines(0, -1, false).await?;
+        let change = LineChange::between(&before, &after);
+
+        return self.record_snapshot(&buffer, before, cursor, change).await;
+    }
+
+    fn is_long_jump(&self, from_line: i64, to_line: i64) -> bool {
+        return (to_line - from_line).unsigned_abs() > self.instance.config.jump_threshold;
+    }
+
+    /// Describes the safety limit the change goes over, if any.
+    fn exceeded_diff_limit(&self, change: &LineChange, old_len: usize) -> Option<String> {
+        let max_deleted = self.instance.config.diff_max_deleted_lines;
+        let max_percent = self.instance.config.diff_max_changed_percent;
+
+        if max_deleted > 0 && change.deleted as u64 > max_deleted {
+            return Some(format!("over the limit of {} deleted lines", max_deleted));
+        }
+
+        let percent = change.changed_percent(old_len);
+        if max_percent > 0 && percent > max_percent {
+            return Some(format!(
+                "changes {}% of the buffer, over the limit of {}%",
+                percent, max_percent
+            ));
+        }
+
+        return None;
+    }
+
+    async fn confirm_diff(&self, change: &LineChange) -> Result<bool, BridgeError> {
+        let choice = self
+            .instance
+            .nvim
+            .call_function(
+                "confirm",
+                vec![
+                    Value::from(format!(
+                        "Serenade wants to delete {} and insert {} lines. Apply?",
+                        change.deleted, change.inserted
+                    )),
+                    Value::from("&Apply\n&Cancel"),
+                    Value::from(2),
+                ],
+            )
+            .await?;
+
+        return Ok(choice.as_i64() == Some(1));
+    }
+
+    async fn diff(&self, source: Option<&String>, cursor: Option<&u64>) -> Result<bool, BridgeError> {
+        let source = source.ok_or_else(|| BridgeError::Protocol(String::from("diff without source")))?;
+        let cursor = cursor.ok_or_else(|| BridgeError::Protocol(String::from("diff without cursor")))?;
+        let nvim = &self.instance.nvim;
+
+        let buffer = self.buffer().await?;
+        let window = self.window(&buffer).await?;
+        let mut cursor_pos = Batch::get_cursor_position_rev(source, cursor);
+        let mut lines: Vec<String> = source.lines().map(|s| s.to_string()).collect();
+        let current = buffer.get_lines(0, -1, false).await?;
+        let change = LineChange::between(&current, &lines);
+
+        if let Some(limit) = self.exceeded_diff_limit(&change, current.len()) {
+            warn!(
+                "Diff {} ({} deleted, {} inserted)\n--- before ---\n{}\n--- after ---\n{}",
+                limit,
+                change.deleted,
+                change.inserted,
+                current.join("\n"),
+                lines.join("\n")
+            );
+
+            let allowed = match self.instance.config.diff_limit_action {
+                DiffLimitAction::Refuse => false,
+                DiffLimitAction::Confirm => self.confirm_diff(&change).await?,
+            };
+
+            if !allowed {
+                nvim.command(&format!(
+                    "echohl WarningMsg | echom \"serenade: refused a diff deleting {} and inserting {} lines, {}\" | echohl None",
+                    change.deleted, change.inserted, limit
+                ))
+                .await?;
                 return Ok(false);
             }
         }
+
+        if self.instance.config.normalize_indent {
+            let style = IndentStyle::for_buffer(&buffer).await?;
+            for &i in &change.inserted_lines {
+                let (line, old_len, new_len) = style.reindent(&lines[i]);
+                lines[i] = line;
+
+                if i as u64 + 1 == cursor_pos.0 {
+                    let col = cursor_pos.1 as usize;
+                    cursor_pos.1 = if col >= old_len {
+                        (col - old_len + new_len) as u64
+                    } else {
+                        cmp::min(col, new_len) as u64
+                    };
+                }
+            }
+        }
+
+        let previous_cursor = match &window {
+            Some(window) => window.get_cursor().await?,
+            None => (1, 0),
+        };
+        self.record_snapshot(&buffer, current, previous_cursor, change).await?;
+
+        let views = view::save_views(nvim, &buffer).await?;
+        let line_count = lines.len() as i64;
+
+        buffer.set_lines(0, -1, false, lines).await?;
+
+        if let Some(window) = window {
+            if self.is_long_jump(previous_cursor.0, cursor_pos.0 as i64) {
+                let line = cmp::min(previous_cursor.0, cmp::max(line_count, 1));
+                window.set_cursor((line, previous_cursor.1)).await?;
+                nvim.command("normal! m'").await?;
+            }
+
+            window.set_cursor((cursor_pos.0 as i64, cursor_pos.1 as i64)).await?;
+        }
+        view::restore_views(nvim, &views, cursor_pos.0 as i64, line_count).await?;
+
+        return Ok(true);
+    }
+}
+
+/// A `[line, column]` pair as returned by `nvim_win_get_cursor` and `nvim_buf_get_mark`.
+fn position(value: Option<&Value>) -> (i64, i64) {
+    let pair: Vec<i64> = value
+        .and_then(|v| v.as_array())
+        .map(|v| v.iter().filter_map(|p| p.as_i64()).collect())
+        .unwrap_or_default();
+
+    return (pair.first().cloned().unwrap_or(1), pair.get(1).cloned().unwrap_or(0));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(message: &str) -> CallbackResult {
+        return CallbackResult::Error(CallbackFailure {
+            message: String::from(message),
+        });
+    }
+
+    #[test]
+    fn aggregate_completes_an_empty_batch() {
+        assert_eq!(Batch::aggregate(Vec::new()), CallbackResult::Completed);
+    }
+
+    #[test]
+    fn aggregate_keeps_the_editor_state_and_the_first_failure() {
+        let state = CallbackResult::EditorState(SerenadeStateData::default());
+        let results = vec![state, CallbackResult::Completed];
+        assert_eq!(
+            Batch::aggregate(results),
+            CallbackResult::EditorState(SerenadeStateData::default())
+        );
+
+        let results = vec![CallbackResult::Completed, error("first"), error("second")];
+        assert_eq!(Batch::aggregate(results), error("first"));
     }
 }
@@ -0,0 +1,150 @@
+use crate::diff::LineChange;
+use crate::neovim::Buffer;
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+/// Buffer contents captured right before a voice edit was applied.
+pub struct Snapshot {
+    pub buffer: Buffer,
+    pub name: String,
+    pub taken_at: SystemTime,
+    pub lines: Vec<String>,
+    pub cursor: (i64, i64),
+    pub change: LineChange,
+}
+
+impl Snapshot {
+    pub fn summary(&self) -> String {
+        let file_name = self.name.rsplit('/').next().unwrap_or("");
+        let age = match SystemTime::now().duration_since(self.taken_at) {
+            Ok(v) => v.as_secs(),
+            _ => 0,
+        };
+
+        return format!(
+            "{}  +{} -{}  {}s ago",
+            if file_name.is_empty() { "[No Name]" } else { file_name },
+            self.change.inserted,
+            self.change.deleted,
+            age
+        );
+    }
+}
+
+/// Bounded ring of snapshots, oldest first.
+pub struct SnapshotHistory {
+    capacity: usize,
+    snapshots: VecDeque<Snapshot>,
+}
+
+impl SnapshotHistory {
+    pub fn new(capacity: usize) -> SnapshotHistory {
+        SnapshotHistory {
+            capacity,
+            snapshots: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.snapshots.len() > capacity {
+            self.snapshots.pop_front();
+        }
+    }
+
+    pub fn push(&mut self, snapshot: Snapshot) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.snapshots.len() >= self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(snapshot);
+    }
+
+    /// Returns the `n`th most recent snapshot, counting from 1.
+    pub fn get(&self, n: usize) -> Option<&Snapshot> {
+        if n == 0 || n > self.snapshots.len() {
+            return None;
+        }
+        return self.snapshots.get(self.snapshots.len() - n);
+    }
+
+    pub fn newest_first(&self) -> impl Iterator<Item = &Snapshot> {
+        return self.snapshots.iter().rev();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::neovim;
+    use nvim_rs::{Neovim, Value};
+    use tokio::io::AsyncWrite;
+    use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+
+    /// A snapshot of a buffer on a session nobody reads, told apart by its only line.
+    fn snapshot(tag: &str) -> Snapshot {
+        let (reader, writer) = tokio::io::duplex(64);
+        let writer: Box<dyn AsyncWrite + Send + Unpin> = Box::new(writer);
+        let (nvim, _io) = Neovim::new(reader.compat(), writer.compat_write(), neovim::handler().0);
+
+        return Snapshot {
+            buffer: Buffer::new(Value::from(1), nvim),
+            name: String::new(),
+            taken_at: SystemTime::now(),
+            lines: vec![String::from(tag)],
+            cursor: (1, 0),
+            change: LineChange::default(),
+        };
+    }
+
+    fn tags(history: &SnapshotHistory) -> Vec<String> {
+        return history.newest_first().map(|v| v.lines[0].clone()).collect();
+    }
+
+    #[test]
+    fn push_evicts_the_oldest() {
+        let mut history = SnapshotHistory::new(2);
+        history.push(snapshot("a"));
+        history.push(snapshot("b"));
+        history.push(snapshot("c"));
+
+        assert_eq!(tags(&history), vec!["c", "b"]);
+    }
+
+    #[test]
+    fn zero_capacity_keeps_nothing() {
+        let mut history = SnapshotHistory::new(0);
+        history.push(snapshot("a"));
+
+        assert!(tags(&history).is_empty());
+    }
+
+    #[test]
+    fn set_capacity_drops_the_oldest() {
+        let mut history = SnapshotHistory::new(3);
+        history.push(snapshot("a"));
+        history.push(snapshot("b"));
+        history.push(snapshot("c"));
+        history.set_capacity(1);
+
+        assert_eq!(tags(&history), vec!["c"]);
+
+        history.set_capacity(2);
+        history.push(snapshot("d"));
+        assert_eq!(tags(&history), vec!["d", "c"]);
+    }
+
+    #[test]
+    fn get_counts_from_the_newest() {
+        let mut history = SnapshotHistory::new(3);
+        history.push(snapshot("a"));
+        history.push(snapshot("b"));
+
+        assert!(history.get(0).is_none());
+        assert_eq!(history.get(1).map(|v| v.lines[0].as_str()), Some("b"));
+        assert_eq!(history.get(2).map(|v| v.lines[0].as_str()), Some("a"));
+        assert!(history.get(3).is_none());
+    }
+}
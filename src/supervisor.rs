@@ -0,0 +1,236 @@
+use crate::config::{Args, Config};
+use crate::instance::Instance;
+use crate::neovim::{NVimEventHandler, Notifications, Nvim};
+use crate::serenade::{Control, SerenadeEventHandler};
+use crate::snapshot::SnapshotHistory;
+use log::{error, info, warn};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Worker {
+    Neovim,
+    Serenade,
+}
+
+/// Start and stop requests seen so far, replayed to a restarted Serenade worker.
+#[derive(Default)]
+struct ControlState {
+    started: bool,
+    paused: bool,
+}
+
+/// Runs the Neovim and Serenade tasks of a single instance and restarts whichever one panics.
+///
+/// A restarted Serenade worker opens a fresh websocket but keeps the instance id, namespace and
+/// snapshots. The bridge gives up once `max_restarts` restarts happened within `restart_window`.
+struct Supervisor {
+    args: Args,
+    nvim: Nvim,
+    id: String,
+    history: Arc<Mutex<SnapshotHistory>>,
+    config: Config,
+    /// A session has a single handler, so every Neovim worker reads notifications from the same
+    /// receiver.
+    notifications: Arc<tokio::sync::Mutex<Notifications>>,
+    /// Control messages from the Neovim workers, relayed to the current Serenade worker.
+    control_tx: UnboundedSender<Control>,
+    serenade_tx: Arc<Mutex<Option<UnboundedSender<Control>>>>,
+    state: Arc<Mutex<ControlState>>,
+    exits_tx: UnboundedSender<(Worker, bool)>,
+    exits: UnboundedReceiver<(Worker, bool)>,
+    restarts: VecDeque<Instant>,
+}
+
+/// Runs the bridge for a single Neovim session until Neovim exits or the workers keep crashing.
+pub async fn run(nvim: Nvim, notifications: Notifications, args: Args) {
+    let instance = Instance::new(nvim.clone(), &args).await;
+
+    let (control_tx, control_rx) = unbounded_channel();
+    let (exits_tx, exits) = unbounded_channel();
+
+    let mut supervisor = Supervisor {
+        args,
+        nvim,
+        id: instance.id.clone(),
+        history: Arc::clone(&instance.history),
+        config: instance.config.clone(),
+        notifications: Arc::new(tokio::sync::Mutex::new(notifications)),
+        control_tx,
+        serenade_tx: Arc::new(Mutex::new(None)),
+        state: Arc::new(Mutex::new(ControlState::default())),
+        exits_tx,
+        exits,
+        restarts: VecDeque::new(),
+    };
+
+    supervisor.relay(control_rx);
+    supervisor.start_neovim();
+    supervisor.start_serenade(instance);
+    supervisor.supervise().await;
+}
+
+impl Supervisor {
+    async fn supervise(&mut self) {
+        loop {
+            let (worker, panicked) = match self.exits.recv().await {
+                Some(v) => v,
+                None => return,
+            };
+
+            if !panicked {
+                info!("The {:?} worker finished", worker);
+
+                // The Neovim worker finishes first and asks the Serenade one to shut down.
+                if worker == Worker::Serenade {
+                    return;
+                }
+                continue;
+            }
+
+            error!("The {:?} worker panicked", worker);
+
+            if !self.may_restart() {
+                self.notify(&format!(
+                    "serenade: giving up after {} crashes within {}s",
+                    self.restarts.len(),
+                    self.config.restart_window
+                ))
+                .await;
+                return;
+            }
+
+            self.notify(&format!("serenade: restarting after a crash in the {:?} worker", worker))
+                .await;
+
+            match worker {
+                Worker::Neovim => self.start_neovim(),
+                Worker::Serenade => {
+                    let instance = Instance::resume(
+                        self.nvim.clone(),
+                        &self.args,
+                        &self.id,
+                        Arc::clone(&self.history),
+                    )
+                    .await;
+                    self.start_serenade(instance);
+                }
+            }
+        }
+    }
+
+    /// Counts a restart, returning false if there were already too many in the window.
+    fn may_restart(&mut self) -> bool {
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.restart_window);
+
+        while let Some(v) = self.restarts.front() {
+            if now.duration_since(*v) <= window {
+                break;
+            }
+            self.restarts.pop_front();
+        }
+
+        if self.restarts.len() as u64 >= self.config.max_restarts {
+            return false;
+        }
+
+        self.restarts.push_back(now);
+        return true;
+    }
+
+    async fn notify(&self, message: &str) {
+        warn!("{}", message);
+
+        if let Err(e) = self.nvim.err_writeln(message).await {
+            warn!("Unable to notify nvim: {}", e);
+        }
+    }
+
+    /// Forwards control messages to whichever Serenade worker is current, remembering whether
+    /// Serenade was started or paused.
+    fn relay(&self, mut control_rx: UnboundedReceiver<Control>) {
+        let serenade_tx = Arc::clone(&self.serenade_tx);
+        let state = Arc::clone(&self.state);
+
+        tokio::spawn(async move {
+            while let Some(control) = control_rx.recv().await {
+                if let Ok(mut state) = state.lock() {
+                    match control {
+                        Control::Start => {
+                            state.started = true;
+                            state.paused = false;
+                        }
+                        Control::Stop => state.paused = true,
+                        _ => {}
+                    }
+                }
+
+                let sent = match serenade_tx.lock() {
+                    Ok(tx) => match tx.as_ref() {
+                        Some(tx) => tx.send(control).is_ok(),
+                        None => false,
+                    },
+                    _ => false,
+                };
+                if !sent {
+                    warn!("Dropping a control message, the Serenade worker is restarting");
+                }
+            }
+        });
+    }
+
+    fn start_neovim(&self) {
+        let mut handler = NVimEventHandler::new(
+            self.nvim.clone(),
+            Arc::clone(&self.history),
+            self.id.clone(),
+            false,
+            self.control_tx.clone(),
+        );
+        let notifications = Arc::clone(&self.notifications);
+
+        self.spawn(Worker::Neovim, async move {
+            // Only one Neovim worker runs at a time, the lock just hands the receiver over.
+            handler.handle_events(&mut *notifications.lock().await).await;
+        });
+    }
+
+    fn start_serenade(&self, instance: Instance) {
+        let (tx, rx) = unbounded_channel();
+
+        if let Ok(state) = self.state.lock() {
+            if state.started {
+                let _ = tx.send(Control::Start);
+            }
+            if state.paused {
+                let _ = tx.send(Control::Stop);
+            }
+        }
+
+        match self.serenade_tx.lock() {
+            Ok(mut v) => *v = Some(tx),
+            _ => error!("Unable to lock the control channel"),
+        }
+
+        let mut serenade = SerenadeEventHandler::new(instance.config.clone(), vec![instance], rx);
+        self.spawn(Worker::Serenade, async move { serenade.handle_events().await });
+    }
+
+    /// Runs a worker in its own task, reporting to `supervise` when it finishes or panics.
+    fn spawn<F: Future<Output = ()> + Send + 'static>(&self, worker: Worker, f: F) {
+        let exits_tx = self.exits_tx.clone();
+        let task = tokio::spawn(f);
+
+        tokio::spawn(async move {
+            let panicked = match task.await {
+                Ok(()) => false,
+                Err(e) => e.is_panic(),
+            };
+            let _ = exits_tx.send((worker, panicked));
+        });
+    }
+}
@@ -0,0 +1,81 @@
+use crate::neovim::{Buffer, Nvim, Window};
+use nvim_rs::error::CallError;
+
+/// Contents of a buffer before the batch first touched it.
+struct BufferState {
+    buffer: Buffer,
+    number: i64,
+    lines: Vec<String>,
+    window: Window,
+    cursor: (i64, i64),
+}
+
+/// Buffers touched by a batch of commands, so the whole batch can be undone if one fails.
+///
+/// Only buffer contents and cursors are restored; files already written by `SAVE` stay written.
+pub struct Transaction {
+    current: Option<Buffer>,
+    buffers: Vec<BufferState>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        return Transaction {
+            current: None,
+            buffers: Vec::new(),
+        };
+    }
+
+    /// Saves `buffer`, unless it was already saved earlier in the batch.
+    pub async fn capture(&mut self, nvim: &Nvim, buffer: &Buffer) -> Result<(), Box<CallError>> {
+        let number = buffer.get_number().await?;
+
+        if self.current.is_none() {
+            self.current = Some(buffer.clone());
+        }
+        if self.buffers.iter().any(|v| v.number == number) {
+            return Ok(());
+        }
+
+        let window = nvim.get_current_win().await?;
+        let cursor = window.get_cursor().await?;
+        let lines = buffer.get_lines(0, -1, false).await?;
+
+        self.buffers.push(BufferState {
+            buffer: buffer.clone(),
+            number,
+            lines,
+            window,
+            cursor,
+        });
+
+        return Ok(());
+    }
+
+    /// Puts every saved buffer back the way it was, then returns to the buffer the batch started in.
+    pub async fn rollback(self, nvim: &Nvim) -> Result<(), Box<CallError>> {
+        for state in &self.buffers {
+            if !state.buffer.is_valid().await? {
+                continue;
+            }
+
+            state.buffer.set_lines(0, -1, false, state.lines.clone()).await?;
+        }
+
+        if let Some(current) = &self.current {
+            if current.is_valid().await? {
+                nvim.set_current_buf(current).await?;
+            }
+        }
+
+        for state in &self.buffers {
+            if !state.window.is_valid().await? || state.window.get_buf().await? != state.buffer {
+                continue;
+            }
+
+            state.window.set_cursor(state.cursor).await?;
+        }
+
+        return Ok(());
+    }
+}
@@ -0,0 +1,90 @@
+use crate::neovim::{Buffer, Nvim};
+use nvim_rs::error::CallError;
+use nvim_rs::Value;
+use std::cmp;
+
+/// Scroll and cursor state of one window showing a buffer that is about to be rewritten.
+pub struct SavedView {
+    win_id: i64,
+    topline: i64,
+    height: i64,
+    lnum: i64,
+    col: i64,
+}
+
+/// Saves the view of every window, in every tab, that shows `buffer`.
+pub async fn save_views(nvim: &Nvim, buffer: &Buffer) -> Result<Vec<SavedView>, Box<CallError>> {
+    let bufnr = buffer.get_number().await?;
+    let info = nvim.call_function("getwininfo", vec![]).await?;
+    let mut views = Vec::new();
+
+    for window in info.as_array().map(|v| &v[..]).unwrap_or(&[]) {
+        if get_i64(window, "bufnr") != Some(bufnr) {
+            continue;
+        }
+
+        let win_id = get_i64(window, "winid").unwrap_or(0);
+        let pos = nvim.call_function("getcurpos", vec![Value::from(win_id)]).await?;
+        let pos: Vec<i64> = pos
+            .as_array()
+            .map(|v| v.iter().filter_map(|p| p.as_i64()).collect())
+            .unwrap_or_default();
+
+        views.push(SavedView {
+            win_id,
+            topline: get_i64(window, "topline").unwrap_or(1),
+            height: get_i64(window, "height").unwrap_or(1),
+            lnum: pos.get(1).cloned().unwrap_or(1),
+            col: pos.get(2).cloned().unwrap_or(1) - 1,
+        });
+    }
+
+    return Ok(views);
+}
+
+/// Puts saved views back after the buffer was rewritten.
+///
+/// The current window keeps its old topline only while `cursor_line` is still on screen,
+/// otherwise the scroll done by moving the cursor is left alone. Other windows get their
+/// topline and cursor back.
+pub async fn restore_views(
+    nvim: &Nvim,
+    views: &[SavedView],
+    cursor_line: i64,
+    line_count: i64,
+) -> Result<(), Box<CallError>> {
+    let current = nvim.call_function("win_getid", vec![]).await?.as_i64().unwrap_or(0);
+
+    for view in views {
+        let topline = cmp::min(view.topline, cmp::max(line_count, 1));
+        let command = if view.win_id == current {
+            if cursor_line < view.topline || cursor_line >= view.topline + view.height {
+                continue;
+            }
+            format!("call winrestview({{'topline': {}}})", topline)
+        } else {
+            format!(
+                "call winrestview({{'topline': {}, 'lnum': {}, 'col': {}}})",
+                topline,
+                cmp::min(view.lnum, cmp::max(line_count, 1)),
+                view.col
+            )
+        };
+
+        nvim.call_function(
+            "win_execute",
+            vec![Value::from(view.win_id), Value::from(command)],
+        )
+        .await?;
+    }
+
+    return Ok(());
+}
+
+fn get_i64(dict: &Value, key: &str) -> Option<i64> {
+    return dict
+        .as_map()?
+        .iter()
+        .find(|(k, _)| k.as_str() == Some(key))
+        .and_then(|(_, v)| v.as_i64());
+}